@@ -0,0 +1,353 @@
+//! ChiselStore configuration.
+
+use crate::errors::StoreError;
+use crate::log_storage::{
+    LogStorage, LogStorageFactory, MemoryLogStorage, SegmentedLogStorage, DEFAULT_SEGMENT_SIZE,
+};
+use derivative::Derivative;
+use fs2::FileExt;
+use sqlite::OpenFlags;
+use std::fs::{File, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::time::Duration;
+
+/// File in the data directory that a store holds a lock on.
+const LOCK_FILE_NAME: &str = "chiselstore.lock";
+
+/// Distinguishes the in-memory databases of stores started in the same process.
+static NEXT_MEMDB_INSTANCE: AtomicU64 = AtomicU64::new(0);
+
+/// Where the SQLite database lives.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StorageMode {
+    /// Database file and write-ahead log in the data directory.
+    Disk,
+    /// Database shared by all pooled connections in memory, using SQLite's
+    /// `memdb` VFS. Nothing but snapshot images is written to disk, and a
+    /// restarted node recovers its state from the cluster.
+    ///
+    /// Without a write-ahead log, decided entries are not applied while a
+    /// read is in progress, which holds up consensus, so reads have to be
+    /// short.
+    Memory,
+}
+
+/// How the log is compacted once a compaction is due.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CompactionMode {
+    /// Trim entries that every replica has decided.
+    Trim,
+    /// Snapshot entries decided by a quorum. Replicas that lag behind catch
+    /// up from the snapshot.
+    Snapshot,
+}
+
+/// Log compaction policy.
+///
+/// A compaction is due as soon as any of the configured thresholds is reached.
+/// The default policy never compacts the log.
+#[derive(Clone, Debug)]
+pub struct CompactionPolicy {
+    /// Compact once the log holds this many entries.
+    pub max_entries: Option<u64>,
+    /// Compact once the entries of the log take up this many bytes.
+    pub max_bytes: Option<u64>,
+    /// Compact a non-empty log at least this often.
+    pub interval: Option<Duration>,
+    /// How to compact the log.
+    pub mode: CompactionMode,
+}
+
+impl Default for CompactionPolicy {
+    fn default() -> Self {
+        Self::disabled()
+    }
+}
+
+impl CompactionPolicy {
+    /// Never compact the log.
+    pub fn disabled() -> Self {
+        Self {
+            max_entries: None,
+            max_bytes: None,
+            interval: None,
+            mode: CompactionMode::Snapshot,
+        }
+    }
+
+    /// Snapshot the log once it holds 100,000 entries or 64 MiB.
+    pub fn recommended() -> Self {
+        Self {
+            max_entries: Some(100_000),
+            max_bytes: Some(64 * 1024 * 1024),
+            interval: None,
+            mode: CompactionMode::Snapshot,
+        }
+    }
+
+    pub(crate) fn is_due(&self, entries: u64, bytes: u64, since_last: Duration) -> bool {
+        if entries == 0 {
+            return false;
+        }
+        self.max_entries.map_or(false, |max| entries >= max)
+            || self.max_bytes.map_or(false, |max| bytes >= max)
+            || self.interval.map_or(false, |interval| since_last >= interval)
+    }
+}
+
+/// Leader lease.
+///
+/// A leader holds a lease once a quorum of nodes confirmed its leadership,
+/// and serves linearizable reads from its local database without another
+/// quorum round while the lease is valid. Nodes that confirmed the leader
+/// do not support a new leader until the lease they granted ran out.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LeaderLease {
+    /// How long a confirmation of the leader is valid for.
+    pub duration: Duration,
+    /// Bound on how far the clocks of two nodes drift apart during a lease.
+    /// The leader stops using its lease this much earlier.
+    pub max_clock_drift: Duration,
+}
+
+impl Default for LeaderLease {
+    fn default() -> Self {
+        Self {
+            duration: Duration::from_millis(500),
+            max_clock_drift: Duration::from_millis(50),
+        }
+    }
+}
+
+impl LeaderLease {
+    /// How long the leader may rely on a confirmation.
+    pub(crate) fn leader_duration(&self) -> Duration {
+        self.duration.saturating_sub(self.max_clock_drift)
+    }
+}
+
+/// Store configuration.
+#[derive(Clone, Derivative)]
+#[derivative(Debug)]
+pub struct StoreConfig {
+    /// Directory holding the database, write-ahead log and snapshots.
+    data_dir: PathBuf,
+    /// Database file name, `{id}` is replaced with the node ID.
+    db_file_name: String,
+    /// Flags the database connections are opened with.
+    #[derivative(Debug = "ignore")]
+    open_flags: OpenFlags,
+    /// Connection pool size.
+    conn_pool_size: usize,
+    /// Where the database lives.
+    storage_mode: StorageMode,
+    /// Creates the log storage, if not the default one of the storage mode.
+    #[derivative(Debug = "ignore")]
+    log_storage: Option<LogStorageFactory>,
+    /// Names the in-memory databases of this store apart from those of other stores.
+    memdb_instance: u64,
+    /// Log compaction policy.
+    compaction_policy: CompactionPolicy,
+    /// Leader lease, if leases are enabled.
+    leader_lease: Option<LeaderLease>,
+    /// Number of latest requests per client whose results are kept.
+    dedup_window: u64,
+    /// Number of requests after which the results of a client that sent
+    /// none of them are dropped.
+    dedup_expiry: u64,
+}
+
+impl Default for StoreConfig {
+    fn default() -> Self {
+        Self {
+            data_dir: PathBuf::from("."),
+            db_file_name: String::from("node{id}.db"),
+            open_flags: OpenFlags::new().set_read_write().set_create().set_no_mutex(),
+            conn_pool_size: 20,
+            storage_mode: StorageMode::Disk,
+            log_storage: None,
+            memdb_instance: 0,
+            compaction_policy: CompactionPolicy::disabled(),
+            leader_lease: None,
+            dedup_window: 1000,
+            dedup_expiry: 100000,
+        }
+    }
+}
+
+impl StoreConfig {
+    /// Set the directory holding the database, write-ahead log and snapshots.
+    ///
+    /// The directory must exist, and only one process can use it at a time.
+    pub fn set_data_dir<P: Into<PathBuf>>(&mut self, data_dir: P) {
+        self.data_dir = data_dir.into();
+    }
+
+    /// Set the database file name template, `{id}` is replaced with the node ID.
+    ///
+    /// The write-ahead log and snapshots are named after the database file.
+    pub fn set_db_file_name<S: Into<String>>(&mut self, template: S) {
+        self.db_file_name = template.into();
+    }
+
+    /// Set the flags the database connections are opened with.
+    pub fn set_open_flags(&mut self, open_flags: OpenFlags) {
+        self.open_flags = open_flags;
+    }
+
+    /// Set the number of pooled database connections.
+    pub fn set_conn_pool_size(&mut self, conn_pool_size: usize) {
+        self.conn_pool_size = conn_pool_size;
+    }
+
+    /// Set where the database lives.
+    ///
+    /// In [`StorageMode::Memory`] the data directory only holds snapshot images.
+    pub fn set_storage_mode(&mut self, storage_mode: StorageMode) {
+        self.storage_mode = storage_mode;
+    }
+
+    /// Set how the log storage of a node is created for each configuration.
+    ///
+    /// By default, the log is kept in a [`SegmentedLogStorage`] in the data
+    /// directory, or in a [`MemoryLogStorage`] in [`StorageMode::Memory`].
+    pub fn set_log_storage<F>(&mut self, factory: F)
+    where
+        F: Fn(&StoreConfig, u64, u32) -> Result<Box<dyn LogStorage>, StoreError> + Send + Sync + 'static,
+    {
+        self.log_storage = Some(Arc::new(factory));
+    }
+
+    /// Set the log compaction policy.
+    ///
+    /// Compaction is disabled by default. The leader compacts on its election
+    /// loop and does not handle messages meanwhile, so a snapshot pauses the
+    /// cluster for as long as taking an image of the database takes.
+    pub fn set_compaction_policy(&mut self, compaction_policy: CompactionPolicy) {
+        self.compaction_policy = compaction_policy;
+    }
+
+    /// Set the leader lease, or disable leases with `None`.
+    ///
+    /// Leases are disabled by default. While a node honours a lease it
+    /// granted, it does not support a new leader, which delays failover by
+    /// up to the lease duration.
+    pub fn set_leader_lease(&mut self, leader_lease: Option<LeaderLease>) {
+        self.leader_lease = leader_lease;
+    }
+
+    /// Set the number of latest requests per client whose results are kept
+    /// to answer retries.
+    ///
+    /// Older requests of the client fail with [`StoreError::RequestExpired`].
+    /// All nodes of a cluster must use the same window.
+    pub fn set_dedup_window(&mut self, dedup_window: u64) {
+        self.dedup_window = dedup_window;
+    }
+
+    /// Set the number of requests, from all clients, after which the results
+    /// of a client that sent none of them are dropped.
+    ///
+    /// This bounds the results kept for clients that went away. Age is
+    /// counted in requests rather than time, so that all nodes drop the same
+    /// results. A retry of a dropped request takes effect again. All nodes of
+    /// a cluster must use the same expiry.
+    pub fn set_dedup_expiry(&mut self, dedup_expiry: u64) {
+        self.dedup_expiry = dedup_expiry;
+    }
+
+    pub(crate) fn open_flags(&self) -> OpenFlags {
+        self.open_flags
+    }
+
+    pub(crate) fn conn_pool_size(&self) -> usize {
+        self.conn_pool_size
+    }
+
+    pub(crate) fn storage_mode(&self) -> StorageMode {
+        self.storage_mode
+    }
+
+    pub(crate) fn compaction_policy(&self) -> CompactionPolicy {
+        self.compaction_policy.clone()
+    }
+
+    pub(crate) fn leader_lease(&self) -> Option<LeaderLease> {
+        self.leader_lease
+    }
+
+    pub(crate) fn dedup_window(&self) -> u64 {
+        self.dedup_window
+    }
+
+    pub(crate) fn dedup_expiry(&self) -> u64 {
+        self.dedup_expiry
+    }
+
+    /// Creates the log storage of node `id` for configuration `configuration_id`.
+    pub(crate) fn open_log_storage(&self, id: u64, configuration_id: u32) -> Result<Box<dyn LogStorage>, StoreError> {
+        if let Some(factory) = &self.log_storage {
+            return factory(self, id, configuration_id);
+        }
+        match self.storage_mode {
+            StorageMode::Disk => {
+                let dir = self.node_file_path(id, &format!("-config{}-log", configuration_id));
+                Ok(Box::new(SegmentedLogStorage::open(dir, DEFAULT_SEGMENT_SIZE)?))
+            }
+            StorageMode::Memory => Ok(Box::new(MemoryLogStorage::default())),
+        }
+    }
+
+    /// Give the in-memory databases of this store names no other store of the process uses.
+    pub(crate) fn assign_memdb_instance(&mut self) {
+        self.memdb_instance = NEXT_MEMDB_INSTANCE.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Name of the in-memory database of node `id` in the `memdb` VFS.
+    ///
+    /// The leading slash makes every connection opening the name share the database.
+    pub(crate) fn memdb_name(&self, id: u64) -> String {
+        format!(
+            "/chiselstore{}-{}",
+            self.memdb_instance,
+            self.db_file_name.replace("{id}", &id.to_string())
+        )
+    }
+
+    /// Path of the database of node `id`.
+    pub(crate) fn db_path(&self, id: u64) -> PathBuf {
+        self.data_dir.join(self.db_file_name.replace("{id}", &id.to_string()))
+    }
+
+    /// Path of a file that belongs to the database of node `id`, such as its
+    /// write-ahead log, named after the database file with `suffix` appended.
+    pub(crate) fn node_file_path(&self, id: u64, suffix: &str) -> PathBuf {
+        let db_path = self.db_path(id);
+        let stem = db_path.file_stem().unwrap_or_default().to_string_lossy().to_string();
+        self.data_dir.join(format!("{}{}", stem, suffix))
+    }
+
+    /// Validate the data directory and lock it for this store.
+    ///
+    /// The lock is held until the returned file is dropped. A directory holds
+    /// the state of one node, so no other store can use it, whatever its id.
+    pub(crate) fn lock_data_dir(&self) -> Result<File, StoreError> {
+        if !Path::new(&self.data_dir).is_dir() {
+            return Err(StoreError::DataDir(format!(
+                "{} does not exist or is not a directory",
+                self.data_dir.display()
+            )));
+        }
+        let path = self.data_dir.join(LOCK_FILE_NAME);
+        let lock = OpenOptions::new().create(true).write(true).open(&path)?;
+        if lock.try_lock_exclusive().is_err() {
+            return Err(StoreError::DataDir(format!(
+                "{} is in use by another store",
+                path.display()
+            )));
+        }
+        Ok(lock)
+    }
+}
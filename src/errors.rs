@@ -8,6 +8,57 @@ pub enum StoreError {
     /// SQLite error.
     #[error("SQLite error: {0}")]
     SQLiteError(#[from] sqlite::Error),
+    /// I/O error, e.g. while accessing the write-ahead log.
+    #[error("I/O error: {0}")]
+    IoError(#[from] std::io::Error),
+    /// The data directory is missing or used by another store.
+    #[error("Data directory error: {0}")]
+    DataDir(String),
+    /// An entry of the persisted log is damaged or missing.
+    #[error("Corrupted log: {0}")]
+    CorruptLog(String),
+    /// A snapshot image does not match its checksum.
+    #[error("Corrupted snapshot: {0}")]
+    CorruptSnapshot(String),
+    /// A named parameter does not appear in the SQL statement.
+    #[error("Unknown parameter: {0}")]
+    UnknownParameter(String),
+    /// A SQL statement takes a different number of positional parameters
+    /// than were given.
+    #[error("Statement takes {0} parameters, but {1} were given")]
+    ParameterCount(usize, usize),
+    /// A statement tries to control the transaction its command is applied in.
+    ///
+    /// Every command is applied in a transaction of its own; statements that
+    /// have to commit together are sent as one batch instead.
+    #[error("Transaction control statement not supported: {0}")]
+    TransactionControl(String),
+    /// A statement that writes to the database was sent as a local read.
+    #[error("Statement is not read-only")]
+    NotReadOnly,
+    /// The deadline of a query passed before it completed.
+    ///
+    /// A replicated statement may still take effect afterwards.
+    #[error("Query timed out")]
+    Timeout,
+    /// A replicated command was dropped by a change of leader or
+    /// configuration before it was decided, and can be retried.
+    #[error("Proposal dropped")]
+    ProposalDropped,
+    /// A retried request is older than the requests whose results are kept
+    /// for its client, and may or may not have taken effect.
+    #[error("Request expired")]
+    RequestExpired,
+    /// A retried request carries other statements than the request with its
+    /// ID that took effect.
+    #[error("Request does not match the request it retries")]
+    RequestMismatch,
+    /// Decided entries could not be applied to the database of this node.
+    ///
+    /// The entries are applied again once another entry is decided, so the
+    /// command may still take effect.
+    #[error("Failed to apply decided entries: {0}")]
+    ApplyFailed(String),
     /// This node is not a leader and cannot therefore execute the command.
     #[error("Node is not a leader")]
     NotLeader,
@@ -0,0 +1,358 @@
+//! ChiselStore statement execution.
+//!
+//! SQL is run directly against the SQLite C API, which lets a command hold
+//! several statements, binds parameters to each of them and reads columns
+//! with their SQLite storage class.
+
+use crate::errors::StoreError;
+use crate::server::{Column, QueryResults, QueryRow};
+use crate::value::{Params, Value};
+use sqlite::Connection;
+use sqlite3_sys as ffi;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int, c_void};
+
+/// Prepared statement, finalized when dropped.
+struct RawStatement(*mut ffi::sqlite3_stmt);
+
+impl Drop for RawStatement {
+    fn drop(&mut self) {
+        unsafe {
+            ffi::sqlite3_finalize(self.0);
+        }
+    }
+}
+
+/// Runs every statement in `sql` with `params` bound to it, and returns the
+/// rows of all of them, described by the columns of the first statement
+/// that has any, together with the rows the statements changed.
+pub(crate) fn execute(conn: &mut Connection, sql: &str, params: &Params) -> Result<QueryResults, StoreError> {
+    let mut rows = vec![];
+    let mut results = run(conn, sql, params, false, &mut |_, row| {
+        rows.push(row);
+        true
+    })?;
+    results.rows = rows;
+    Ok(results)
+}
+
+/// Runs `sql` like [`execute`], but fails before running any statement that
+/// could write to the database.
+pub(crate) fn execute_read_only(conn: &mut Connection, sql: &str, params: &Params) -> Result<QueryResults, StoreError> {
+    let mut rows = vec![];
+    let mut results = run(conn, sql, params, true, &mut |_, row| {
+        rows.push(row);
+        true
+    })?;
+    results.rows = rows;
+    Ok(results)
+}
+
+/// Runs `sql` like [`execute_read_only`], but hands every row to `on_row`
+/// together with the result columns as soon as SQLite produces it, instead
+/// of returning the rows. Stops early once `on_row` returns false.
+pub(crate) fn stream_read_only(
+    conn: &mut Connection,
+    sql: &str,
+    params: &Params,
+    on_row: &mut dyn FnMut(&[Column], QueryRow) -> bool,
+) -> Result<QueryResults, StoreError> {
+    run(conn, sql, params, true, on_row)
+}
+
+fn run(
+    conn: &mut Connection,
+    sql: &str,
+    params: &Params,
+    read_only: bool,
+    on_row: &mut dyn FnMut(&[Column], QueryRow) -> bool,
+) -> Result<QueryResults, StoreError> {
+    let db = conn.as_raw();
+    let sql = CString::new(sql).map_err(|_| error(None, "SQL contains a NUL character"))?;
+    let mut columns = vec![];
+    let mut rows_affected = 0;
+    let mut bound_names = vec![false; named_count(params)];
+    let mut takes_params = false;
+    let mut tail: *const c_char = sql.as_ptr();
+    unsafe {
+        // rowid 0 is never handed out by SQLite itself
+        ffi::sqlite3_set_last_insert_rowid(db, 0);
+        'statements: while *tail != 0 {
+            let mut raw = std::ptr::null_mut();
+            let mut next = std::ptr::null();
+            if ffi::sqlite3_prepare_v2(db, tail, -1, &mut raw, &mut next) != ffi::SQLITE_OK {
+                return Err(last_error(db));
+            }
+            tail = next;
+            if raw.is_null() {
+                // only whitespace or a comment
+                continue;
+            }
+            let stmt = RawStatement(raw);
+            check_transaction_control(&stmt)?;
+            if read_only && ffi::sqlite3_stmt_readonly(stmt.0) == 0 {
+                return Err(StoreError::NotReadOnly);
+            }
+            bind_params(db, &stmt, params, &mut bound_names)?;
+            takes_params |= ffi::sqlite3_bind_parameter_count(stmt.0) > 0;
+            if columns.is_empty() {
+                columns = read_columns(&stmt);
+            }
+            let total_changes = ffi::sqlite3_total_changes(db);
+            loop {
+                match ffi::sqlite3_step(stmt.0) {
+                    ffi::SQLITE_ROW => {
+                        if !on_row(&columns, read_row(&stmt)) {
+                            break 'statements;
+                        }
+                    }
+                    ffi::SQLITE_DONE => break,
+                    _ => return Err(last_error(db)),
+                }
+            }
+            // sqlite3_changes() keeps the count of the last INSERT, UPDATE or
+            // DELETE, so only take it if this statement changed anything
+            if ffi::sqlite3_total_changes(db) != total_changes {
+                rows_affected += ffi::sqlite3_changes(db) as u64;
+            }
+        }
+    }
+    let last_insert_rowid = match unsafe { ffi::sqlite3_last_insert_rowid(db) } {
+        0 => None,
+        rowid => Some(rowid),
+    };
+    match params {
+        Params::Named(values) => {
+            if let Some(i) = bound_names.iter().position(|bound| !bound) {
+                return Err(StoreError::UnknownParameter(values[i].0.clone()));
+            }
+        }
+        Params::Positional(values) if !values.is_empty() && !takes_params => {
+            return Err(StoreError::ParameterCount(0, values.len()));
+        }
+        _ => {}
+    }
+    Ok(QueryResults {
+        columns,
+        rows: vec![],
+        rows_affected,
+        last_insert_rowid,
+        applied_idx: 0,
+        config_id: 0,
+    })
+}
+
+/// Returns true if every statement in `sql` only reads the database.
+///
+/// Statements that fail to prepare or change the state of the connection,
+/// which SQLite considers read-only, count as writes.
+pub(crate) fn is_read_only(conn: &mut Connection, sql: &str) -> bool {
+    let db = conn.as_raw();
+    let sql = match CString::new(sql) {
+        Ok(sql) => sql,
+        Err(_) => return false,
+    };
+    let mut tail: *const c_char = sql.as_ptr();
+    unsafe {
+        while *tail != 0 {
+            let mut raw = std::ptr::null_mut();
+            let mut next = std::ptr::null();
+            if ffi::sqlite3_prepare_v2(db, tail, -1, &mut raw, &mut next) != ffi::SQLITE_OK {
+                return false;
+            }
+            tail = next;
+            if raw.is_null() {
+                continue;
+            }
+            let stmt = RawStatement(raw);
+            if ffi::sqlite3_stmt_readonly(stmt.0) == 0 {
+                return false;
+            }
+            let sql = string(ffi::sqlite3_sql(stmt.0)).unwrap_or_default();
+            let keyword = first_keyword(&sql);
+            if TRANSACTION_CONTROL.iter().chain(CONNECTION_STATE.iter()).any(|k| k.eq_ignore_ascii_case(keyword)) {
+                return false;
+            }
+        }
+    }
+    true
+}
+
+/// Statements that change the state of a connection rather than the database.
+const CONNECTION_STATE: [&str; 3] = ["ATTACH", "DETACH", "PRAGMA"];
+
+/// Statements that begin or end a transaction. The store applies every
+/// command in a transaction of its own, so commands may not control it.
+const TRANSACTION_CONTROL: [&str; 6] = ["BEGIN", "COMMIT", "END", "ROLLBACK", "SAVEPOINT", "RELEASE"];
+
+unsafe fn check_transaction_control(stmt: &RawStatement) -> Result<(), StoreError> {
+    let sql = string(ffi::sqlite3_sql(stmt.0)).unwrap_or_default();
+    let keyword = first_keyword(&sql);
+    if TRANSACTION_CONTROL.iter().any(|k| k.eq_ignore_ascii_case(keyword)) {
+        return Err(StoreError::TransactionControl(keyword.to_ascii_uppercase()));
+    }
+    Ok(())
+}
+
+/// Returns the first keyword of `sql`, skipping whitespace and comments.
+fn first_keyword(sql: &str) -> &str {
+    let mut rest = sql;
+    loop {
+        rest = rest.trim_start();
+        if let Some(comment) = rest.strip_prefix("--") {
+            rest = comment.find('\n').map_or("", |end| &comment[end..]);
+        } else if let Some(comment) = rest.strip_prefix("/*") {
+            rest = comment.find("*/").map_or("", |end| &comment[end + 2..]);
+        } else {
+            break;
+        }
+    }
+    let end = rest.find(|c: char| !c.is_ascii_alphabetic()).unwrap_or(rest.len());
+    &rest[..end]
+}
+
+fn named_count(params: &Params) -> usize {
+    match params {
+        Params::Named(values) => values.len(),
+        _ => 0,
+    }
+}
+
+/// Binds `params` to `stmt`, recording in `bound_names` which named parameters it uses.
+unsafe fn bind_params(
+    db: *mut ffi::sqlite3,
+    stmt: &RawStatement,
+    params: &Params,
+    bound_names: &mut [bool],
+) -> Result<(), StoreError> {
+    match params {
+        Params::None => check_positional_count(stmt, 0)?,
+        Params::Positional(values) => {
+            check_positional_count(stmt, values.len())?;
+            for (i, value) in values.iter().enumerate() {
+                bind_value(db, stmt, (i + 1) as c_int, value)?;
+            }
+        }
+        Params::Named(values) => {
+            for (i, (name, value)) in values.iter().enumerate() {
+                let name = match name.chars().next() {
+                    Some(':') | Some('@') | Some('$') => name.clone(),
+                    _ => format!(":{}", name),
+                };
+                let name = CString::new(name).map_err(|_| StoreError::UnknownParameter(values[i].0.clone()))?;
+                let idx = ffi::sqlite3_bind_parameter_index(stmt.0, name.as_ptr());
+                if idx > 0 {
+                    bind_value(db, stmt, idx, value)?;
+                    bound_names[i] = true;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Checks that `stmt` takes `given` positional parameters, unless it takes
+/// none, as it may share a command with statements that do.
+unsafe fn check_positional_count(stmt: &RawStatement, given: usize) -> Result<(), StoreError> {
+    let count = ffi::sqlite3_bind_parameter_count(stmt.0) as usize;
+    if count != 0 && count != given {
+        return Err(StoreError::ParameterCount(count, given));
+    }
+    Ok(())
+}
+
+unsafe fn bind_value(db: *mut ffi::sqlite3, stmt: &RawStatement, i: c_int, value: &Value) -> Result<(), StoreError> {
+    let code = match value {
+        Value::Null => ffi::sqlite3_bind_null(stmt.0, i),
+        Value::Integer(v) => ffi::sqlite3_bind_int64(stmt.0, i, *v),
+        Value::Real(v) => ffi::sqlite3_bind_double(stmt.0, i, *v),
+        Value::Text(v) => ffi::sqlite3_bind_text(
+            stmt.0,
+            i,
+            v.as_ptr() as *const c_char,
+            v.len() as c_int,
+            transient(),
+        ),
+        Value::Blob(v) => ffi::sqlite3_bind_blob(
+            stmt.0,
+            i,
+            v.as_ptr() as *const c_void,
+            v.len() as c_int,
+            transient(),
+        ),
+    };
+    if code != ffi::SQLITE_OK {
+        return Err(last_error(db));
+    }
+    Ok(())
+}
+
+/// Destructor telling SQLite to copy bound text and blobs.
+fn transient() -> ffi::sqlite3_destructor_type {
+    unsafe { Some(std::mem::transmute::<isize, unsafe extern "C" fn(*mut c_void)>(-1)) }
+}
+
+unsafe fn read_columns(stmt: &RawStatement) -> Vec<Column> {
+    let count = ffi::sqlite3_column_count(stmt.0);
+    (0..count)
+        .map(|i| Column {
+            name: string(ffi::sqlite3_column_name(stmt.0, i)).unwrap_or_default(),
+            decl_type: string(ffi::sqlite3_column_decltype(stmt.0, i)),
+            table: string(ffi::sqlite3_column_table_name(stmt.0, i)),
+        })
+        .collect()
+}
+
+unsafe fn string(ptr: *const c_char) -> Option<String> {
+    if ptr.is_null() {
+        None
+    } else {
+        Some(CStr::from_ptr(ptr).to_string_lossy().into_owned())
+    }
+}
+
+unsafe fn read_row(stmt: &RawStatement) -> QueryRow {
+    let count = ffi::sqlite3_column_count(stmt.0);
+    QueryRow {
+        values: (0..count).map(|i| read_value(stmt, i)).collect(),
+    }
+}
+
+unsafe fn read_value(stmt: &RawStatement, i: c_int) -> Value {
+    match ffi::sqlite3_column_type(stmt.0, i) {
+        ffi::SQLITE_INTEGER => Value::Integer(ffi::sqlite3_column_int64(stmt.0, i)),
+        ffi::SQLITE_FLOAT => Value::Real(ffi::sqlite3_column_double(stmt.0, i)),
+        ffi::SQLITE_TEXT => {
+            let text = ffi::sqlite3_column_text(stmt.0, i) as *const u8;
+            let len = ffi::sqlite3_column_bytes(stmt.0, i) as usize;
+            Value::Text(String::from_utf8_lossy(bytes(text, len)).into_owned())
+        }
+        ffi::SQLITE_BLOB => {
+            let blob = ffi::sqlite3_column_blob(stmt.0, i) as *const u8;
+            let len = ffi::sqlite3_column_bytes(stmt.0, i) as usize;
+            Value::Blob(bytes(blob, len).to_vec())
+        }
+        _ => Value::Null,
+    }
+}
+
+unsafe fn bytes<'a>(ptr: *const u8, len: usize) -> &'a [u8] {
+    if ptr.is_null() {
+        &[]
+    } else {
+        std::slice::from_raw_parts(ptr, len)
+    }
+}
+
+unsafe fn last_error(db: *mut ffi::sqlite3) -> StoreError {
+    let code = ffi::sqlite3_errcode(db);
+    let message = CStr::from_ptr(ffi::sqlite3_errmsg(db)).to_string_lossy().into_owned();
+    error(Some(code as isize), message)
+}
+
+fn error<S: Into<String>>(code: Option<isize>, message: S) -> StoreError {
+    StoreError::SQLiteError(sqlite::Error {
+        code,
+        message: Some(message.into()),
+    })
+}
@@ -17,18 +17,28 @@
 //! As ChiselStore uses the Raft consensus algorithm, it provides strong
 //! consistency (linearizability) by default. SQL statements on a cluster of
 //! ChiselStore appear to execute as if there is only one copy of the data
-//! because SQL statements execute on the Raft cluster leader node. As strong
-//! consistency limits performance, ChiselStore provides an optional
-//! consistency [`Consistency::RelaxedReads`] mode, allowing clients to
-//! perform read operations on the local node. The relaxed read mode can,
+//! because SQL statements execute on the Raft cluster leader node. Read-only
+//! statements are recognized and served by the node a client is connected
+//! to, once the leader confirmed that the node is up to date, so that they
+//! do not grow the replicated log. As strong consistency limits
+//! performance, ChiselStore provides an optional consistency
+//! [`Consistency::RelaxedReads`] mode, allowing clients to perform read
+//! operations on the local node. The relaxed read mode can,
 //! however, result in reading stale data so use it with caution.
+//! [`Consistency::BoundedStaleness`] only reads locally while the node is
+//! known to be close enough behind the leader.
+//!
+//! The replicated log of SQL statements is compacted according to a
+//! [`CompactionPolicy`](config::CompactionPolicy), either by trimming entries
+//! that every node has decided or by replacing them with a snapshot of the
+//! SQLite database, which lagging nodes use to catch up. Compaction is
+//! disabled by default.
 //!
 //! ChiselStore is currently not suitable for production use because it lacks
-//! support for Raft snapshots and joint consensus. That is, the replicated
-//! log of SQL statements is never truncated, and it is not possible for
-//! nodes to join and leave a cluster dynamically. There is, however, a plan
-//! to implement support for the missing features to make ChiselStore suitable
-//! for production use cases.
+//! support for joint consensus. That is, it is not possible for nodes to join
+//! and leave a cluster dynamically. There is, however, a plan to implement
+//! support for the missing features to make ChiselStore suitable for
+//! production use cases.
 //!
 //! ChiselStore comes with batteries included and embedding it to your
 //! application as simple as:
@@ -93,12 +103,28 @@
 
 #![warn(missing_docs, missing_debug_implementations, rust_2018_idioms)]
 
+pub mod config;
 pub mod errors;
+mod exec;
+pub mod log_storage;
+mod memdb;
 pub mod rpc;
+mod segment;
 pub mod server;
+pub mod snapshot;
 pub mod util;
+pub mod value;
+mod wal;
 
+pub use config::StoreConfig;
 pub use errors::StoreError;
+pub use log_storage::LogStorage;
+pub use server::CommandId;
+pub use server::Consistency;
+pub use server::RequestId;
+pub use server::Statement;
 pub use server::StoreCommand;
 pub use server::StoreServer;
 pub use server::StoreTransport;
+pub use snapshot::SQLiteSnapshot;
+pub use value::{Params, Value};
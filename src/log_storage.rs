@@ -0,0 +1,491 @@
+//! ChiselStore log storage.
+//!
+//! The replicated log and the Sequence Paxos state of a node live behind the
+//! [`LogStorage`] trait, apart from the SQLite database that decided entries
+//! are applied to. Log indices are relative to the compacted log, like the
+//! indices of the Sequence Paxos storage.
+
+use crate::config::StoreConfig;
+use crate::errors::StoreError;
+use crate::segment::SegmentedLog;
+use crate::snapshot::SQLiteSnapshot;
+use crate::wal::{Wal, WalRecord, WalState};
+use crate::StoreCommand;
+use omnipaxos_core::{ballot_leader_election::Ballot, storage::StopSignEntry};
+use std::fmt::Debug;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Size after which [`SegmentedLogStorage`] starts a new segment by default.
+pub const DEFAULT_SEGMENT_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Number of records after which the state log of [`SegmentedLogStorage`] is rewritten.
+const STATE_CHECKPOINT_RECORDS: usize = 1024;
+
+/// Creates the log storage of a node for a configuration, given the store
+/// configuration, the node ID and the configuration ID.
+pub type LogStorageFactory =
+    Arc<dyn Fn(&StoreConfig, u64, u32) -> Result<Box<dyn LogStorage>, StoreError> + Send + Sync>;
+
+/// Storage of the replicated log and the Sequence Paxos state.
+pub trait LogStorage: Debug + Send {
+    /// Appends `entries` to the end of the log.
+    fn append_entries(&mut self, entries: Vec<StoreCommand>) -> Result<(), StoreError>;
+
+    /// Removes the entries from `len` onwards.
+    fn truncate(&mut self, len: u64) -> Result<(), StoreError>;
+
+    /// Removes the first `idx` entries.
+    fn trim(&mut self, idx: u64) -> Result<(), StoreError>;
+
+    /// Entries from `from` up to, but not including, `to`.
+    fn get_entries(&self, from: u64, to: u64) -> &[StoreCommand];
+
+    /// Entries from `from` to the end of the log.
+    fn get_suffix(&self, from: u64) -> &[StoreCommand];
+
+    /// Number of entries in the log.
+    fn get_log_len(&self) -> u64;
+
+    /// Stores the promised round.
+    fn set_promise(&mut self, n_prom: Ballot) -> Result<(), StoreError>;
+
+    /// Promised round.
+    fn get_promise(&self) -> Ballot;
+
+    /// Stores the accepted round.
+    fn set_accepted_round(&mut self, acc_round: Ballot) -> Result<(), StoreError>;
+
+    /// Accepted round.
+    fn get_accepted_round(&self) -> Ballot;
+
+    /// Stores the length of the decided log.
+    fn set_decided_idx(&mut self, ld: u64) -> Result<(), StoreError>;
+
+    /// Length of the decided log.
+    fn get_decided_idx(&self) -> u64;
+
+    /// Stores the garbage collected index.
+    fn set_compacted_idx(&mut self, idx: u64) -> Result<(), StoreError>;
+
+    /// Garbage collected index.
+    fn get_compacted_idx(&self) -> u64;
+
+    /// Stores the StopSign.
+    fn set_stopsign(&mut self, ss: StopSignEntry) -> Result<(), StoreError>;
+
+    /// Stored StopSign.
+    fn get_stopsign(&self) -> Option<StopSignEntry>;
+
+    /// Stores the snapshot.
+    fn set_snapshot(&mut self, snapshot: SQLiteSnapshot) -> Result<(), StoreError>;
+
+    /// Stored snapshot.
+    fn get_snapshot(&self) -> Option<SQLiteSnapshot>;
+
+    /// Returns true if the storage survives a restart of the node.
+    fn is_durable(&self) -> bool;
+
+    /// Returns true if the storage holds no state at all.
+    fn is_empty(&self) -> bool {
+        self.get_log_len() == 0
+            && self.get_decided_idx() == 0
+            && self.get_compacted_idx() == 0
+            && self.get_stopsign().is_none()
+            && self.get_snapshot().is_none()
+            && self.get_promise() == Ballot::default()
+    }
+}
+
+/// Log storage that keeps everything in memory.
+#[derive(Debug, Default)]
+pub struct MemoryLogStorage {
+    /// Vector which contains all the replicated entries in-memory.
+    log: Vec<StoreCommand>,
+    /// Last promised round.
+    n_prom: Ballot,
+    /// Last accepted round.
+    acc_round: Ballot,
+    /// Length of the decided log.
+    ld: u64,
+    /// Garbage collected index.
+    trimmed_idx: u64,
+    /// Stored StopSign
+    stopsign: Option<StopSignEntry>,
+    /// Stored snapshot
+    snapshot: Option<SQLiteSnapshot>,
+}
+
+impl MemoryLogStorage {
+    fn wal_state(&self) -> WalState {
+        WalState {
+            log: self.log.clone(),
+            ..self.paxos_state()
+        }
+    }
+
+    /// Everything but the log.
+    fn paxos_state(&self) -> WalState {
+        WalState {
+            log: vec![],
+            n_prom: self.n_prom,
+            acc_round: self.acc_round,
+            ld: self.ld,
+            trimmed_idx: self.trimmed_idx,
+            stopsign: self.stopsign.clone(),
+            snapshot: self.snapshot.clone(),
+        }
+    }
+}
+
+impl From<WalState> for MemoryLogStorage {
+    fn from(state: WalState) -> Self {
+        MemoryLogStorage {
+            log: state.log,
+            n_prom: state.n_prom,
+            acc_round: state.acc_round,
+            ld: state.ld,
+            trimmed_idx: state.trimmed_idx,
+            stopsign: state.stopsign,
+            snapshot: state.snapshot,
+        }
+    }
+}
+
+impl LogStorage for MemoryLogStorage {
+    fn append_entries(&mut self, mut entries: Vec<StoreCommand>) -> Result<(), StoreError> {
+        self.log.append(&mut entries);
+        Ok(())
+    }
+
+    fn truncate(&mut self, len: u64) -> Result<(), StoreError> {
+        self.log.truncate(len as usize);
+        Ok(())
+    }
+
+    fn trim(&mut self, idx: u64) -> Result<(), StoreError> {
+        let idx = std::cmp::min(idx as usize, self.log.len());
+        self.log.drain(0..idx);
+        Ok(())
+    }
+
+    fn get_entries(&self, from: u64, to: u64) -> &[StoreCommand] {
+        self.log.get(from as usize..to as usize).unwrap_or(&[])
+    }
+
+    fn get_suffix(&self, from: u64) -> &[StoreCommand] {
+        self.log.get(from as usize..).unwrap_or(&[])
+    }
+
+    fn get_log_len(&self) -> u64 {
+        self.log.len() as u64
+    }
+
+    fn set_promise(&mut self, n_prom: Ballot) -> Result<(), StoreError> {
+        self.n_prom = n_prom;
+        Ok(())
+    }
+
+    fn get_promise(&self) -> Ballot {
+        self.n_prom
+    }
+
+    fn set_accepted_round(&mut self, acc_round: Ballot) -> Result<(), StoreError> {
+        self.acc_round = acc_round;
+        Ok(())
+    }
+
+    fn get_accepted_round(&self) -> Ballot {
+        self.acc_round
+    }
+
+    fn set_decided_idx(&mut self, ld: u64) -> Result<(), StoreError> {
+        self.ld = ld;
+        Ok(())
+    }
+
+    fn get_decided_idx(&self) -> u64 {
+        self.ld
+    }
+
+    fn set_compacted_idx(&mut self, idx: u64) -> Result<(), StoreError> {
+        self.trimmed_idx = idx;
+        Ok(())
+    }
+
+    fn get_compacted_idx(&self) -> u64 {
+        self.trimmed_idx
+    }
+
+    fn set_stopsign(&mut self, ss: StopSignEntry) -> Result<(), StoreError> {
+        self.stopsign = Some(ss);
+        Ok(())
+    }
+
+    fn get_stopsign(&self) -> Option<StopSignEntry> {
+        self.stopsign.clone()
+    }
+
+    fn set_snapshot(&mut self, snapshot: SQLiteSnapshot) -> Result<(), StoreError> {
+        self.snapshot = Some(snapshot);
+        Ok(())
+    }
+
+    fn get_snapshot(&self) -> Option<SQLiteSnapshot> {
+        self.snapshot.clone()
+    }
+
+    fn is_durable(&self) -> bool {
+        false
+    }
+}
+
+/// Log storage that keeps everything in memory and persists every change
+/// in a write-ahead log file.
+#[derive(Debug)]
+pub struct FileLogStorage {
+    wal: Wal,
+    state: MemoryLogStorage,
+}
+
+impl FileLogStorage {
+    /// Opens the log storage in the file at `path`, recovering any state it holds.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, StoreError> {
+        let (wal, state) = Wal::open(path)?;
+        Ok(FileLogStorage {
+            wal,
+            state: state.into(),
+        })
+    }
+}
+
+impl LogStorage for FileLogStorage {
+    fn append_entries(&mut self, entries: Vec<StoreCommand>) -> Result<(), StoreError> {
+        self.wal.append(WalRecord::Append(entries.clone()))?;
+        self.state.append_entries(entries)
+    }
+
+    fn truncate(&mut self, len: u64) -> Result<(), StoreError> {
+        self.wal.append(WalRecord::Truncate(len))?;
+        self.state.truncate(len)
+    }
+
+    fn trim(&mut self, idx: u64) -> Result<(), StoreError> {
+        self.wal.append(WalRecord::Trim(idx))?;
+        self.state.trim(idx)
+    }
+
+    fn get_entries(&self, from: u64, to: u64) -> &[StoreCommand] {
+        self.state.get_entries(from, to)
+    }
+
+    fn get_suffix(&self, from: u64) -> &[StoreCommand] {
+        self.state.get_suffix(from)
+    }
+
+    fn get_log_len(&self) -> u64 {
+        self.state.get_log_len()
+    }
+
+    fn set_promise(&mut self, n_prom: Ballot) -> Result<(), StoreError> {
+        self.wal.append(WalRecord::Promise(n_prom))?;
+        self.state.set_promise(n_prom)
+    }
+
+    fn get_promise(&self) -> Ballot {
+        self.state.get_promise()
+    }
+
+    fn set_accepted_round(&mut self, acc_round: Ballot) -> Result<(), StoreError> {
+        self.wal.append(WalRecord::AcceptedRound(acc_round))?;
+        self.state.set_accepted_round(acc_round)
+    }
+
+    fn get_accepted_round(&self) -> Ballot {
+        self.state.get_accepted_round()
+    }
+
+    fn set_decided_idx(&mut self, ld: u64) -> Result<(), StoreError> {
+        self.wal.append(WalRecord::DecidedIdx(ld))?;
+        self.state.set_decided_idx(ld)
+    }
+
+    fn get_decided_idx(&self) -> u64 {
+        self.state.get_decided_idx()
+    }
+
+    fn set_compacted_idx(&mut self, idx: u64) -> Result<(), StoreError> {
+        self.state.set_compacted_idx(idx)?;
+        // Rewrite the write-ahead log so that it does not outgrow the trimmed log.
+        self.wal.checkpoint(&self.state.wal_state())
+    }
+
+    fn get_compacted_idx(&self) -> u64 {
+        self.state.get_compacted_idx()
+    }
+
+    fn set_stopsign(&mut self, ss: StopSignEntry) -> Result<(), StoreError> {
+        self.wal.append(WalRecord::StopSign(ss.clone()))?;
+        self.state.set_stopsign(ss)
+    }
+
+    fn get_stopsign(&self) -> Option<StopSignEntry> {
+        self.state.get_stopsign()
+    }
+
+    fn set_snapshot(&mut self, snapshot: SQLiteSnapshot) -> Result<(), StoreError> {
+        self.wal.append(WalRecord::Snapshot(snapshot.clone()))?;
+        self.state.set_snapshot(snapshot)
+    }
+
+    fn get_snapshot(&self) -> Option<SQLiteSnapshot> {
+        self.state.get_snapshot()
+    }
+
+    fn is_durable(&self) -> bool {
+        true
+    }
+}
+
+/// Log storage that persists entries in checksummed segment files, and the
+/// rest of the Sequence Paxos state in a small write-ahead log next to them.
+///
+/// Corrupted entries are detected when the storage is opened. Compaction
+/// deletes the segments that hold only compacted entries.
+///
+/// Like [`FileLogStorage`], it keeps a copy of every entry that is not
+/// compacted yet in memory, so the log has to fit in memory between compactions.
+#[derive(Debug)]
+pub struct SegmentedLogStorage {
+    segments: SegmentedLog,
+    /// Write-ahead log of the Sequence Paxos state.
+    state_wal: Wal,
+    /// Records appended to `state_wal` since it was last rewritten.
+    state_records: usize,
+    state: MemoryLogStorage,
+}
+
+impl SegmentedLogStorage {
+    /// Opens the log storage in the directory `dir`, recovering any state it
+    /// holds. A new segment is started once a segment holds `segment_size` bytes.
+    pub fn open<P: AsRef<Path>>(dir: P, segment_size: u64) -> Result<Self, StoreError> {
+        let dir = dir.as_ref();
+        std::fs::create_dir_all(dir)?;
+        let (state_wal, state) = Wal::open(dir.join("state.wal"))?;
+        let start_idx = state.trimmed_idx;
+        let (segments, entries) = SegmentedLog::open(dir, segment_size, start_idx)?;
+        let mut state = MemoryLogStorage::from(state);
+        state.log = entries;
+        Ok(SegmentedLogStorage {
+            segments,
+            state_wal,
+            state_records: 0,
+            state,
+        })
+    }
+
+    fn persist(&mut self, record: WalRecord) -> Result<(), StoreError> {
+        self.state_wal.append(record)?;
+        self.state_records += 1;
+        if self.state_records >= STATE_CHECKPOINT_RECORDS {
+            self.checkpoint()?;
+        }
+        Ok(())
+    }
+
+    fn checkpoint(&mut self) -> Result<(), StoreError> {
+        self.state_wal.checkpoint(&self.state.paxos_state())?;
+        self.state_records = 0;
+        Ok(())
+    }
+}
+
+impl LogStorage for SegmentedLogStorage {
+    fn append_entries(&mut self, entries: Vec<StoreCommand>) -> Result<(), StoreError> {
+        self.segments.append(&entries)?;
+        self.state.append_entries(entries)
+    }
+
+    fn truncate(&mut self, len: u64) -> Result<(), StoreError> {
+        // the segments end where the entries kept in memory end
+        let start_idx = self.segments.end_idx() - self.state.get_log_len();
+        self.segments.truncate(start_idx + len)?;
+        self.state.truncate(len)
+    }
+
+    fn trim(&mut self, idx: u64) -> Result<(), StoreError> {
+        // segments are deleted once the compacted index is durable
+        self.state.trim(idx)
+    }
+
+    fn get_entries(&self, from: u64, to: u64) -> &[StoreCommand] {
+        self.state.get_entries(from, to)
+    }
+
+    fn get_suffix(&self, from: u64) -> &[StoreCommand] {
+        self.state.get_suffix(from)
+    }
+
+    fn get_log_len(&self) -> u64 {
+        self.state.get_log_len()
+    }
+
+    fn set_promise(&mut self, n_prom: Ballot) -> Result<(), StoreError> {
+        self.state.set_promise(n_prom)?;
+        self.persist(WalRecord::Promise(n_prom))
+    }
+
+    fn get_promise(&self) -> Ballot {
+        self.state.get_promise()
+    }
+
+    fn set_accepted_round(&mut self, acc_round: Ballot) -> Result<(), StoreError> {
+        self.state.set_accepted_round(acc_round)?;
+        self.persist(WalRecord::AcceptedRound(acc_round))
+    }
+
+    fn get_accepted_round(&self) -> Ballot {
+        self.state.get_accepted_round()
+    }
+
+    fn set_decided_idx(&mut self, ld: u64) -> Result<(), StoreError> {
+        self.state.set_decided_idx(ld)?;
+        self.persist(WalRecord::DecidedIdx(ld))
+    }
+
+    fn get_decided_idx(&self) -> u64 {
+        self.state.get_decided_idx()
+    }
+
+    fn set_compacted_idx(&mut self, idx: u64) -> Result<(), StoreError> {
+        self.state.set_compacted_idx(idx)?;
+        self.checkpoint()?;
+        self.segments.trim(idx)
+    }
+
+    fn get_compacted_idx(&self) -> u64 {
+        self.state.get_compacted_idx()
+    }
+
+    fn set_stopsign(&mut self, ss: StopSignEntry) -> Result<(), StoreError> {
+        self.state.set_stopsign(ss.clone())?;
+        self.persist(WalRecord::StopSign(ss))
+    }
+
+    fn get_stopsign(&self) -> Option<StopSignEntry> {
+        self.state.get_stopsign()
+    }
+
+    fn set_snapshot(&mut self, snapshot: SQLiteSnapshot) -> Result<(), StoreError> {
+        self.state.set_snapshot(snapshot.clone())?;
+        self.persist(WalRecord::Snapshot(snapshot))
+    }
+
+    fn get_snapshot(&self) -> Option<SQLiteSnapshot> {
+        self.state.get_snapshot()
+    }
+
+    fn is_durable(&self) -> bool {
+        true
+    }
+}
@@ -0,0 +1,57 @@
+//! ChiselStore in-memory databases.
+//!
+//! SQLite's `memdb` VFS keeps a database in memory and shares it between all
+//! connections of the process that open the same name, as long as the name
+//! starts with a slash. Connections select the VFS with a `file:` URI, which
+//! leaves the default VFS of the process alone.
+
+use crate::errors::StoreError;
+use sqlite::{Connection, OpenFlags};
+use sqlite3_sys as ffi;
+use std::os::raw::c_char;
+
+/// Opens a connection to the shared in-memory database `name`.
+pub(crate) fn open(name: &str, flags: OpenFlags) -> Result<Connection, StoreError> {
+    let memdb = unsafe { ffi::sqlite3_vfs_find(b"memdb\0".as_ptr() as *const c_char) };
+    if memdb.is_null() {
+        return Err(error("the memdb VFS is not available, SQLite 3.36 or later is required"));
+    }
+    let uri = format!("file:{}?vfs=memdb", escape_uri_path(name));
+    Ok(Connection::open_with_flags(uri, flags.set_uri())?)
+}
+
+/// Escapes the characters that end or escape the path of a URI.
+fn escape_uri_path(path: &str) -> String {
+    path.replace('%', "%25").replace('?', "%3f").replace('#', "%23")
+}
+
+/// Copies the whole database of `src` into the database of `dest` in a
+/// single step, replacing its contents.
+///
+/// Unlike `VACUUM INTO` and `ATTACH`, which use the VFS of the connection,
+/// this copies between an in-memory database and a database file.
+pub(crate) fn copy(src: &mut Connection, dest: &mut Connection) -> Result<(), StoreError> {
+    let main = b"main\0".as_ptr() as *const c_char;
+    unsafe {
+        let backup = ffi::sqlite3_backup_init(dest.as_raw(), main, src.as_raw(), main);
+        if backup.is_null() {
+            return Err(error(format!(
+                "cannot copy database, error code {}",
+                ffi::sqlite3_errcode(dest.as_raw())
+            )));
+        }
+        let code = ffi::sqlite3_backup_step(backup, -1);
+        ffi::sqlite3_backup_finish(backup);
+        if code != ffi::SQLITE_DONE {
+            return Err(error(format!("cannot copy database, error code {}", code)));
+        }
+    }
+    Ok(())
+}
+
+fn error<S: Into<String>>(message: S) -> StoreError {
+    StoreError::SQLiteError(sqlite::Error {
+        code: None,
+        message: Some(message.into()),
+    })
+}
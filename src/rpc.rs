@@ -1,13 +1,21 @@
 //! ChiselStore RPC module.
 
 use crate::rpc::proto::rpc_server::Rpc;
-use crate::{StoreCommand, StoreServer, StoreTransport};
+use crate::snapshot::{pin_image, SQLiteSnapshot, SnapshotImage};
+use crate::util::log::log;
+use crate::{CommandId, Consistency, Params, RequestId, Statement, StoreCommand, StoreError, StoreServer, StoreTransport, Value};
 use async_mutex::Mutex;
 use async_trait::async_trait;
 use crossbeam::queue::ArrayQueue;
 use derivative::Derivative;
 use std::collections::HashMap;
+use std::io::SeekFrom;
+use std::pin::Pin;
 use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio::time::{sleep, Duration};
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::{Stream, StreamExt};
 use tonic::{Request, Response, Status};
 use omnipaxos_core::{
     ballot_leader_election::messages::{BLEMessage, HeartbeatMsg, HeartbeatRequest, HeartbeatReply},
@@ -16,6 +24,7 @@ use omnipaxos_core::{
         FirstAccept, AcceptDecide, Accepted, Decide, 
         Compaction, AcceptStopSign, AcceptedStopSign, DecideStopSign,
     },
+    storage::SnapshotType,
     util::{SyncItem}
 };
 
@@ -26,16 +35,23 @@ pub mod proto {
 
 use proto::rpc_client::RpcClient;
 use proto::{
-    Query, QueryResults, QueryRow, Void,
+    Query, QueryResults, QueryRow, BatchReq, BatchResults, Void,
     Ballot, StopSign, PrepareReq, PromiseReq, 
     AcceptSyncReq, FirstAcceptReq, AcceptDecideReq, AcceptedReq, 
     DecideReq, ProposalForwardReq, CompactionReq, ForwardCompactionReq,
-    AcceptStopSignReq, AcceptedStopSignReq, DecideStopSignReq,
+    AcceptStopSignReq, AcceptedStopSignReq, DecideStopSignReq, PrepareRequestReq,
+    SnapshotStatusReq, SnapshotChunk, SnapshotAck,
     HeartbeatRequestReq, HeartbeatReplyReq,
+    ReadIndexReq, ReadIndexReply, LeadershipReq, LeadershipReply,
 };
 
 type NodeAddrFn = dyn Fn(u64) -> String + Send + Sync;
 
+/// Size of the chunks snapshot images are streamed in.
+const SNAPSHOT_CHUNK_SIZE: usize = 1024 * 1024;
+/// How many times a snapshot transfer is resumed before giving up.
+const SNAPSHOT_TRANSFER_ATTEMPTS: u32 = 10;
+
 #[derive(Debug)]
 struct ConnectionPool {
     connections: ArrayQueue<RpcClient<tonic::transport::Channel>>,
@@ -59,11 +75,11 @@ impl ConnectionPool {
         })
     }
 
-    async fn connection<S: ToString>(&self, addr: S) -> RpcClient<tonic::transport::Channel> {
+    async fn connection<S: ToString>(&self, addr: S) -> Result<RpcClient<tonic::transport::Channel>, tonic::transport::Error> {
         let addr = addr.to_string();
         match self.connections.pop() {
-            Some(x) => x,
-            None => RpcClient::connect(addr).await.unwrap(),
+            Some(x) => Ok(x),
+            None => RpcClient::connect(addr).await,
         }
     }
 
@@ -80,16 +96,18 @@ impl Connections {
         Self(Arc::new(Mutex::new(HashMap::new())))
     }
 
-    async fn connection<S: ToString>(&self, addr: S) -> Connection {
-        let mut conns = self.0.lock().await;
+    /// Connection to the node at `addr`. Fails if the node cannot be reached,
+    /// and messages to it are then dropped like messages lost on the way.
+    async fn connection<S: ToString>(&self, addr: S) -> Result<Connection, tonic::transport::Error> {
         let addr = addr.to_string();
-        let pool = conns
-            .entry(addr.clone())
-            .or_insert_with(ConnectionPool::new);
-        Connection {
-            conn: pool.connection(addr).await,
-            pool: pool.clone(),
-        }
+        let pool = {
+            let mut conns = self.0.lock().await;
+            conns.entry(addr.clone()).or_insert_with(ConnectionPool::new).clone()
+        };
+        Ok(Connection {
+            conn: pool.connection(addr).await?,
+            pool,
+        })
     }
 }
 
@@ -112,7 +130,7 @@ impl RpcTransport {
     }
 }
 
-fn ballot_from_proto(b: Ballot) -> omnipaxos_core::ballot_leader_election::Ballot {
+pub(crate) fn ballot_from_proto(b: Ballot) -> omnipaxos_core::ballot_leader_election::Ballot {
     omnipaxos_core::ballot_leader_election::Ballot {
         n: b.n,
         priority: b.priority,
@@ -120,14 +138,103 @@ fn ballot_from_proto(b: Ballot) -> omnipaxos_core::ballot_leader_election::Ballo
     }
 }
 
-fn store_command_from_proto(sc: proto::StoreCommand) -> StoreCommand {
+pub(crate) fn store_command_from_proto(sc: proto::StoreCommand) -> StoreCommand {
+    let id = sc.id.unwrap_or_default();
     StoreCommand {
-        id: sc.id,
-        sql: sc.sql,
+        id: CommandId {
+            node_id: id.node_id,
+            epoch: id.epoch,
+            seq: id.seq,
+        },
+        request: sc.request.map(request_id_from_proto),
+        statements: sc.statements.into_iter().map(statement_from_proto).collect(),
+    }
+}
+
+fn request_id_from_proto(r: proto::RequestId) -> RequestId {
+    RequestId {
+        client_id: r.client_id,
+        seq: r.seq,
+    }
+}
+
+fn statement_from_proto(s: proto::Statement) -> Statement {
+    Statement {
+        sql: s.sql,
+        params: params_from_proto(s.params, s.named_params),
+    }
+}
+
+fn value_from_proto(v: proto::Value) -> Value {
+    use proto::value::Value as V;
+
+    match v.value {
+        None | Some(V::Null(_)) => Value::Null,
+        Some(V::Integer(i)) => Value::Integer(i),
+        Some(V::Real(r)) => Value::Real(r),
+        Some(V::Text(t)) => Value::Text(t),
+        Some(V::Blob(b)) => Value::Blob(b),
+    }
+}
+
+fn params_from_proto(params: Vec<proto::Value>, named_params: Vec<proto::NamedValue>) -> Params {
+    if !named_params.is_empty() {
+        Params::Named(
+            named_params
+                .into_iter()
+                .map(|p| (p.name, p.value.map(value_from_proto).unwrap_or(Value::Null)))
+                .collect(),
+        )
+    } else if !params.is_empty() {
+        Params::Positional(params.into_iter().map(value_from_proto).collect())
+    } else {
+        Params::None
+    }
+}
+
+/// Timeout of a request, sent by the client in the `grpc-timeout` header.
+fn timeout_from_request<T>(request: &Request<T>) -> Option<Duration> {
+    let timeout = request.metadata().get("grpc-timeout")?.to_str().ok()?;
+    if timeout.len() < 2 {
+        return None;
+    }
+    let (value, unit) = timeout.split_at(timeout.len() - 1);
+    let value: u64 = value.parse().ok()?;
+    match unit {
+        "H" => Some(Duration::from_secs(value * 60 * 60)),
+        "M" => Some(Duration::from_secs(value * 60)),
+        "S" => Some(Duration::from_secs(value)),
+        "m" => Some(Duration::from_millis(value)),
+        "u" => Some(Duration::from_micros(value)),
+        "n" => Some(Duration::from_nanos(value)),
+        _ => None,
     }
 }
 
-fn stopsign_from_proto(ss: StopSign) -> omnipaxos_core::storage::StopSign {
+fn status_from_error(e: StoreError) -> Status {
+    match e {
+        StoreError::Timeout => Status::deadline_exceeded(format!("{}", e)),
+        StoreError::ProposalDropped => Status::unavailable(format!("{}", e)),
+        StoreError::RequestExpired => Status::failed_precondition(format!("{}", e)),
+        StoreError::RequestMismatch => Status::invalid_argument(format!("{}", e)),
+        e => Status::internal(format!("{}", e)),
+    }
+}
+
+fn consistency_from_proto(consistency: i32, max_staleness_ms: u64, min_index_config: u32, min_index: u64) -> Consistency {
+    match proto::Consistency::from_i32(consistency) {
+        Some(proto::Consistency::RelaxedReads) => Consistency::RelaxedReads,
+        Some(proto::Consistency::BoundedStaleness) => {
+            Consistency::BoundedStaleness(Duration::from_millis(max_staleness_ms))
+        }
+        Some(proto::Consistency::ReadIndex) => Consistency::ReadIndex,
+        Some(proto::Consistency::MinIndex) => Consistency::MinIndex(min_index_config, min_index),
+        // unknown levels fall back to the strongest one
+        Some(proto::Consistency::Strong) | None => Consistency::Strong,
+    }
+}
+
+pub(crate) fn stopsign_from_proto(ss: StopSign) -> omnipaxos_core::storage::StopSign {
     let config_id = ss.config_id;
     let nodes = ss.nodes;
     let metadata = Some(ss.metadata.into_iter().map(|md| md as u8).collect());
@@ -139,22 +246,48 @@ fn stopsign_from_proto(ss: StopSign) -> omnipaxos_core::storage::StopSign {
     }
 }
 
-fn sync_item_from_proto(si: proto::SyncItem) -> SyncItem<StoreCommand,()> {
+pub(crate) fn snapshot_from_proto(s: proto::Snapshot) -> SQLiteSnapshot {
+    let image = s.image.map(|image| SnapshotImage {
+        idx: image.idx,
+        size: image.size,
+        checksum: image.checksum,
+        path: image.path.into(),
+    });
+    let entries = s.entries.into_iter().map(|sc| store_command_from_proto(sc)).collect();
+
+    SQLiteSnapshot {
+        image,
+        entries,
+    }
+}
+
+fn sync_item_from_proto(si: proto::SyncItem, server: &StoreServer<RpcTransport>) -> Result<SyncItem<StoreCommand, SQLiteSnapshot>, StoreError> {
     match si.item.unwrap() {
         proto::sync_item::Item::Entries(entries) => {
             let entries = entries.store_commands.into_iter().map(|sc| store_command_from_proto(sc)).collect();
-            return SyncItem::Entries(entries);
+            return Ok(SyncItem::Entries(entries));
         },
-        proto::sync_item::Item::Snapshot(_) => {
-            return SyncItem::Snapshot(omnipaxos_core::storage::SnapshotType::Delta(())) // TODO: Support SnapshotType::Complete
+        proto::sync_item::Item::Snapshot(mut s) => {
+            let complete = s.complete;
+            let image = s.image.take();
+            let mut snapshot = snapshot_from_proto(s);
+            if let Some(image) = image {
+                // the image was streamed ahead of this message
+                snapshot.image = Some(server.staged_snapshot_image(image.idx, image.size, image.checksum)?);
+            }
+            let snapshot = match complete {
+                true => SnapshotType::Complete(snapshot),
+                false => SnapshotType::Delta(snapshot),
+            };
+            return Ok(SyncItem::Snapshot(snapshot))
         },
         proto::sync_item::Item::None(_) => {
-            return SyncItem::None
+            return Ok(SyncItem::None)
         },
     }
 }
 
-fn proto_from_ballot(b: omnipaxos_core::ballot_leader_election::Ballot) -> Ballot {
+pub(crate) fn proto_from_ballot(b: omnipaxos_core::ballot_leader_election::Ballot) -> Ballot {
     Ballot {
         n: b.n,
         priority: b.priority,
@@ -162,15 +295,139 @@ fn proto_from_ballot(b: omnipaxos_core::ballot_leader_election::Ballot) -> Ballo
     }
 }
 
-fn proto_from_store_command(sc: StoreCommand) -> proto::StoreCommand {
+pub(crate) fn proto_from_store_command(sc: StoreCommand) -> proto::StoreCommand {
     proto::StoreCommand {
-        id: sc.id,
-        sql: sc.sql,
+        id: Some(proto::CommandId {
+            node_id: sc.id.node_id,
+            epoch: sc.id.epoch,
+            seq: sc.id.seq,
+        }),
+        request: sc.request.map(proto_from_request_id),
+        statements: sc.statements.into_iter().map(proto_from_statement).collect(),
     }
 }
 
-fn proto_from_sync_item(si: SyncItem<StoreCommand, ()>) -> proto::SyncItem {
-    match si {
+fn proto_from_request_id(r: RequestId) -> proto::RequestId {
+    proto::RequestId {
+        client_id: r.client_id,
+        seq: r.seq,
+    }
+}
+
+pub(crate) fn proto_from_statement(s: Statement) -> proto::Statement {
+    let (params, named_params) = proto_from_params(s.params);
+    proto::Statement {
+        sql: s.sql,
+        params,
+        named_params,
+    }
+}
+
+fn proto_from_value(v: Value) -> proto::Value {
+    use proto::value::Value as V;
+
+    let value = match v {
+        Value::Null => V::Null(true),
+        Value::Integer(i) => V::Integer(i),
+        Value::Real(r) => V::Real(r),
+        Value::Text(t) => V::Text(t),
+        Value::Blob(b) => V::Blob(b),
+    };
+    proto::Value { value: Some(value) }
+}
+
+fn proto_from_params(params: Params) -> (Vec<proto::Value>, Vec<proto::NamedValue>) {
+    match params {
+        Params::None => (vec![], vec![]),
+        Params::Positional(values) => (values.into_iter().map(proto_from_value).collect(), vec![]),
+        Params::Named(values) => (
+            vec![],
+            values
+                .into_iter()
+                .map(|(name, value)| proto::NamedValue {
+                    name,
+                    value: Some(proto_from_value(value)),
+                })
+                .collect(),
+        ),
+    }
+}
+
+pub(crate) fn proto_from_query_results(results: crate::server::QueryResults) -> QueryResults {
+    let mut rows = vec![];
+    for row in results.rows {
+        rows.push(QueryRow {
+            values: row.values.into_iter().map(proto_from_value).collect(),
+        })
+    }
+
+    let columns = results
+        .columns
+        .into_iter()
+        .map(|c| proto::Column {
+            name: c.name,
+            decl_type: c.decl_type,
+            table: c.table,
+        })
+        .collect();
+
+    QueryResults {
+        rows,
+        columns,
+        rows_affected: results.rows_affected,
+        last_insert_rowid: results.last_insert_rowid,
+        applied_idx: results.applied_idx,
+        config_id: results.config_id,
+    }
+}
+
+pub(crate) fn query_results_from_proto(results: QueryResults) -> crate::server::QueryResults {
+    let rows = results
+        .rows
+        .into_iter()
+        .map(|row| crate::server::QueryRow {
+            values: row.values.into_iter().map(value_from_proto).collect(),
+        })
+        .collect();
+
+    let columns = results
+        .columns
+        .into_iter()
+        .map(|c| crate::server::Column {
+            name: c.name,
+            decl_type: c.decl_type,
+            table: c.table,
+        })
+        .collect();
+
+    crate::server::QueryResults {
+        columns,
+        rows,
+        rows_affected: results.rows_affected,
+        last_insert_rowid: results.last_insert_rowid,
+        applied_idx: results.applied_idx,
+        config_id: results.config_id,
+    }
+}
+
+pub(crate) fn proto_from_snapshot(s: SQLiteSnapshot) -> proto::Snapshot {
+    let image = s.image.map(|image| proto::SnapshotImage {
+        idx: image.idx,
+        size: image.size,
+        checksum: image.checksum,
+        path: image.path.to_string_lossy().to_string(),
+    });
+
+    proto::Snapshot {
+        complete: false,
+        image,
+        entries: s.entries.into_iter().map(|e| proto_from_store_command(e)).collect(),
+    }
+}
+
+/// Returns `None` for a snapshot type that has no representation on the wire.
+fn proto_from_sync_item(si: SyncItem<StoreCommand, SQLiteSnapshot>) -> Option<proto::SyncItem> {
+    let item = match si {
         SyncItem::Entries(entries) => {
             proto::SyncItem {
                 item: Some(proto::sync_item::Item::Entries(proto::sync_item::Entries {
@@ -178,9 +435,20 @@ fn proto_from_sync_item(si: SyncItem<StoreCommand, ()>) -> proto::SyncItem {
                 }))
             }
         },
-        SyncItem::Snapshot(_) => {
+        SyncItem::Snapshot(snapshot) => {
+            let (complete, snapshot) = match snapshot {
+                SnapshotType::Complete(s) => (true, s),
+                SnapshotType::Delta(s) => (false, s),
+                _ => return None,
+            };
+            let mut snapshot = proto_from_snapshot(snapshot);
+            snapshot.complete = complete;
+            if let Some(image) = snapshot.image.as_mut() {
+                // paths are local to this node
+                image.path = String::new();
+            }
             proto::SyncItem {
-                item: Some(proto::sync_item::Item::Snapshot(true)),
+                item: Some(proto::sync_item::Item::Snapshot(snapshot)),
             }
         },
         SyncItem::None => {
@@ -188,10 +456,92 @@ fn proto_from_sync_item(si: SyncItem<StoreCommand, ()>) -> proto::SyncItem {
                 item: Some(proto::sync_item::Item::None(true)),
             }
         },
+    };
+    Some(item)
+}
+
+/// Returns the snapshot image that has to be streamed ahead of a sync item.
+fn image_of_sync_item(si: &SyncItem<StoreCommand, SQLiteSnapshot>) -> Option<SnapshotImage> {
+    match si {
+        SyncItem::Snapshot(SnapshotType::Complete(s)) | SyncItem::Snapshot(SnapshotType::Delta(s)) => s.image.clone(),
+        _ => None,
     }
 }
 
-fn proto_from_stopsign(ss: omnipaxos_core::storage::StopSign) -> StopSign {
+/// Streams `image` to `to` in checksummed chunks.
+///
+/// The receiver keeps the bytes it got so far, so an interrupted transfer
+/// resumes where it stopped instead of starting over.
+async fn send_snapshot_image(
+    client: &mut RpcClient<tonic::transport::Channel>,
+    from: u64,
+    to: u64,
+    image: SnapshotImage,
+) -> Result<(), Status> {
+    let mut attempts = 0;
+    loop {
+        let req = SnapshotStatusReq {
+            from,
+            to,
+            idx: image.idx,
+            size: image.size,
+            checksum: image.checksum,
+        };
+        let received = client.snapshot_status(Request::new(req)).await?.into_inner().received;
+        if received >= image.size {
+            return Ok(());
+        }
+        if attempts == SNAPSHOT_TRANSFER_ATTEMPTS {
+            return Err(Status::aborted("Snapshot transfer did not complete"));
+        }
+        attempts += 1;
+
+        let chunks = snapshot_chunks(from, to, image.clone(), received);
+        if client.install_snapshot(Request::new(chunks)).await.is_err() {
+            sleep(Duration::from_millis(100 * attempts as u64)).await;
+        }
+    }
+}
+
+/// Reads `image` from `offset` onwards into a stream of chunks.
+fn snapshot_chunks(from: u64, to: u64, image: SnapshotImage, offset: u64) -> ReceiverStream<SnapshotChunk> {
+    let (tx, rx) = tokio::sync::mpsc::channel(4);
+    tokio::task::spawn(async move {
+        let mut file = match tokio::fs::File::open(&image.path).await {
+            Ok(file) => file,
+            Err(_) => return,
+        };
+        if file.seek(SeekFrom::Start(offset)).await.is_err() {
+            return;
+        }
+        let mut offset = offset;
+        while offset < image.size {
+            let len = std::cmp::min(SNAPSHOT_CHUNK_SIZE as u64, image.size - offset) as usize;
+            let mut data = vec![0; len];
+            if file.read_exact(&mut data).await.is_err() {
+                return;
+            }
+            let chunk = SnapshotChunk {
+                from,
+                to,
+                idx: image.idx,
+                size: image.size,
+                checksum: image.checksum,
+                offset,
+                chunk_checksum: crc32fast::hash(&data),
+                data,
+            };
+            // the receiving end went away, the transfer is resumed later
+            if tx.send(chunk).await.is_err() {
+                return;
+            }
+            offset += len as u64;
+        }
+    });
+    ReceiverStream::new(rx)
+}
+
+pub(crate) fn proto_from_stopsign(ss: omnipaxos_core::storage::StopSign) -> StopSign {
     let metadata: Vec<u32> = match ss.metadata {
         Some(md) => {
             md.into_iter().map(|md| md as u32).collect()
@@ -207,7 +557,7 @@ fn proto_from_stopsign(ss: omnipaxos_core::storage::StopSign) -> StopSign {
 
 #[async_trait]
 impl StoreTransport for RpcTransport {
-    fn send_sp(&self, to_id: u64, msg: Message<StoreCommand, ()>) {
+    fn send_sp(&self, to_id: u64, msg: Message<StoreCommand, SQLiteSnapshot>) {
         match msg.msg {
             PaxosMsg::Prepare(prepare) => {
                 let from = msg.from;
@@ -230,7 +580,10 @@ impl StoreTransport for RpcTransport {
                 let peer = (self.node_addr)(to_id);
                 let pool = self.connections.clone();
                 tokio::task::spawn(async move {
-                    let mut client = pool.connection(peer).await;
+                    let mut client = match pool.connection(peer).await {
+                        Ok(client) => client,
+                        Err(_) => return,
+                    };
                     let req = tonic::Request::new(req.clone());
                     client.conn.prepare(req).await.unwrap();
                 });
@@ -241,9 +594,15 @@ impl StoreTransport for RpcTransport {
 
                 let n = Some(proto_from_ballot(promise.n));
                 let n_accepted = Some(proto_from_ballot(promise.n_accepted));
+                let image = promise.sync_item.as_ref().and_then(image_of_sync_item);
                 let sync_item: Option<proto::SyncItem> = match promise.sync_item {
-                    Some(si) => {
-                        Some(proto_from_sync_item(si))
+                    Some(si) => match proto_from_sync_item(si) {
+                        Some(sync_item) => Some(sync_item),
+                        None => {
+                            // the message is dropped as if it was lost, for Paxos to recover from
+                            log(format!("Unexpected snapshot type in promise from {} to {}", from, to));
+                            return;
+                        },
                     },
                     None => None,
                 };
@@ -269,10 +628,26 @@ impl StoreTransport for RpcTransport {
 
                 let peer = (self.node_addr)(to_id);
                 let pool = self.connections.clone();
+                // the image is kept until it has been streamed
+                let pin = image.as_ref().map(|image| pin_image(&image.path));
                 tokio::task::spawn(async move {
-                    let mut client = pool.connection(peer).await;
+                    let mut client = match pool.connection(peer).await {
+                        Ok(client) => client,
+                        Err(_) => return,
+                    };
+                    if let Some(image) = image {
+                        // the message is dropped as if it was lost, for Paxos to recover from
+                        if let Err(e) = send_snapshot_image(&mut client.conn, from, to, image).await {
+                            log(format!("Snapshot transfer from {} to {} failed: {}", from, to, e));
+                            return;
+                        }
+                    }
+                    drop(pin);
                     let req = tonic::Request::new(req.clone());
-                    client.conn.promise(req).await.unwrap();
+                    if let Err(e) = client.conn.promise(req).await {
+                        // the message is dropped as if it was lost, for Paxos to recover from
+                        log(format!("Promise from {} to {} failed: {}", from, to, e));
+                    }
                 });
             },
             PaxosMsg::AcceptSync(accept_sync) => {
@@ -280,7 +655,15 @@ impl StoreTransport for RpcTransport {
                 let to = msg.to;
 
                 let n = Some(proto_from_ballot(accept_sync.n));
-                let sync_item = Some(proto_from_sync_item(accept_sync.sync_item));
+                let image = image_of_sync_item(&accept_sync.sync_item);
+                let sync_item = match proto_from_sync_item(accept_sync.sync_item) {
+                    Some(sync_item) => Some(sync_item),
+                    None => {
+                        // the message is dropped as if it was lost, for Paxos to recover from
+                        log(format!("Unexpected snapshot type in sync from {} to {}", from, to));
+                        return;
+                    },
+                };
                 let sync_idx = accept_sync.sync_idx;
                 let decide_idx = accept_sync.decide_idx;
                 let stop_sign: Option<StopSign> = match accept_sync.stopsign {
@@ -302,10 +685,26 @@ impl StoreTransport for RpcTransport {
 
                 let peer = (self.node_addr)(to_id);
                 let pool = self.connections.clone();
+                // the image is kept until it has been streamed
+                let pin = image.as_ref().map(|image| pin_image(&image.path));
                 tokio::task::spawn(async move {
-                    let mut client = pool.connection(peer).await;
+                    let mut client = match pool.connection(peer).await {
+                        Ok(client) => client,
+                        Err(_) => return,
+                    };
+                    if let Some(image) = image {
+                        // the message is dropped as if it was lost, for Paxos to recover from
+                        if let Err(e) = send_snapshot_image(&mut client.conn, from, to, image).await {
+                            log(format!("Snapshot transfer from {} to {} failed: {}", from, to, e));
+                            return;
+                        }
+                    }
+                    drop(pin);
                     let req = tonic::Request::new(req.clone());
-                    client.conn.accept_sync(req).await.unwrap();
+                    if let Err(e) = client.conn.accept_sync(req).await {
+                        // the message is dropped as if it was lost, for Paxos to recover from
+                        log(format!("Sync from {} to {} failed: {}", from, to, e));
+                    }
                 });
             },
             PaxosMsg::FirstAccept(first_accept) => {
@@ -325,7 +724,10 @@ impl StoreTransport for RpcTransport {
                 let peer = (self.node_addr)(to_id);
                 let pool = self.connections.clone();
                 tokio::task::spawn(async move {
-                    let mut client = pool.connection(peer).await;
+                    let mut client = match pool.connection(peer).await {
+                        Ok(client) => client,
+                        Err(_) => return,
+                    };
                     let req = tonic::Request::new(req.clone());
                     client.conn.first_accept(req).await.unwrap();
                 });
@@ -349,7 +751,10 @@ impl StoreTransport for RpcTransport {
                 let peer = (self.node_addr)(to_id);
                 let pool = self.connections.clone();
                 tokio::task::spawn(async move {
-                    let mut client = pool.connection(peer).await;
+                    let mut client = match pool.connection(peer).await {
+                        Ok(client) => client,
+                        Err(_) => return,
+                    };
                     let req = tonic::Request::new(req.clone());
                     client.conn.accept_decide(req).await.unwrap();
                 });
@@ -371,7 +776,10 @@ impl StoreTransport for RpcTransport {
                 let peer = (self.node_addr)(to_id);
                 let pool = self.connections.clone();
                 tokio::task::spawn(async move {
-                    let mut client = pool.connection(peer).await;
+                    let mut client = match pool.connection(peer).await {
+                        Ok(client) => client,
+                        Err(_) => return,
+                    };
                     let req = tonic::Request::new(req.clone());
                     client.conn.accepted(req).await.unwrap();
                 });
@@ -393,7 +801,10 @@ impl StoreTransport for RpcTransport {
                 let peer = (self.node_addr)(to_id);
                 let pool = self.connections.clone();
                 tokio::task::spawn(async move {
-                    let mut client = pool.connection(peer).await;
+                    let mut client = match pool.connection(peer).await {
+                        Ok(client) => client,
+                        Err(_) => return,
+                    };
                     let req = tonic::Request::new(req.clone());
                     client.conn.decide(req).await.unwrap();
                 });
@@ -413,7 +824,10 @@ impl StoreTransport for RpcTransport {
                 let peer = (self.node_addr)(to_id);
                 let pool = self.connections.clone();
                 tokio::task::spawn(async move {
-                    let mut client = pool.connection(peer).await;
+                    let mut client = match pool.connection(peer).await {
+                        Ok(client) => client,
+                        Err(_) => return,
+                    };
                     let req = tonic::Request::new(req.clone());
                     client.conn.proposal_forward(req).await.unwrap();
                 });
@@ -442,7 +856,10 @@ impl StoreTransport for RpcTransport {
                 let peer = (self.node_addr)(to_id);
                 let pool = self.connections.clone();
                 tokio::task::spawn(async move {
-                    let mut client = pool.connection(peer).await;
+                    let mut client = match pool.connection(peer).await {
+                        Ok(client) => client,
+                        Err(_) => return,
+                    };
                     let req = tonic::Request::new(req.clone());
                     client.conn.compaction(req).await.unwrap();
                 });
@@ -471,7 +888,10 @@ impl StoreTransport for RpcTransport {
                 let peer = (self.node_addr)(to_id);
                 let pool = self.connections.clone();
                 tokio::task::spawn(async move {
-                    let mut client = pool.connection(peer).await;
+                    let mut client = match pool.connection(peer).await {
+                        Ok(client) => client,
+                        Err(_) => return,
+                    };
                     let req = tonic::Request::new(req.clone());
                     client.conn.forward_compaction(req).await.unwrap();
                 });
@@ -493,7 +913,10 @@ impl StoreTransport for RpcTransport {
                 let peer = (self.node_addr)(to_id);
                 let pool = self.connections.clone();
                 tokio::task::spawn(async move {
-                    let mut client = pool.connection(peer).await;
+                    let mut client = match pool.connection(peer).await {
+                        Ok(client) => client,
+                        Err(_) => return,
+                    };
                     let req = tonic::Request::new(req.clone());
                     client.conn.accept_stop_sign(req).await.unwrap();
                 });
@@ -513,7 +936,10 @@ impl StoreTransport for RpcTransport {
                 let peer = (self.node_addr)(to_id);
                 let pool = self.connections.clone();
                 tokio::task::spawn(async move {
-                    let mut client = pool.connection(peer).await;
+                    let mut client = match pool.connection(peer).await {
+                        Ok(client) => client,
+                        Err(_) => return,
+                    };
                     let req = tonic::Request::new(req.clone());
                     client.conn.accepted_stop_sign(req).await.unwrap();
                 });
@@ -533,11 +959,34 @@ impl StoreTransport for RpcTransport {
                 let peer = (self.node_addr)(to_id);
                 let pool = self.connections.clone();
                 tokio::task::spawn(async move {
-                    let mut client = pool.connection(peer).await;
+                    let mut client = match pool.connection(peer).await {
+                        Ok(client) => client,
+                        Err(_) => return,
+                    };
                     let req = tonic::Request::new(req.clone());
                     client.conn.decide_stop_sign(req).await.unwrap();
                 });
             },
+            PaxosMsg::PrepareReq => {
+                let from = msg.from;
+                let to = msg.to;
+
+                let req = PrepareRequestReq {
+                    from,
+                    to,
+                };
+
+                let peer = (self.node_addr)(to_id);
+                let pool = self.connections.clone();
+                tokio::task::spawn(async move {
+                    let mut client = match pool.connection(peer).await {
+                        Ok(client) => client,
+                        Err(_) => return,
+                    };
+                    let req = tonic::Request::new(req.clone());
+                    client.conn.prepare_request(req).await.unwrap();
+                });
+            },
             _ => panic!("Missing implementation for send message"),
         };
     }
@@ -559,7 +1008,10 @@ impl StoreTransport for RpcTransport {
                 let peer = (self.node_addr)(to_id);
                 let pool = self.connections.clone();
                 tokio::task::spawn(async move {
-                    let mut client = pool.connection(peer).await;
+                    let mut client = match pool.connection(peer).await {
+                        Ok(client) => client,
+                        Err(_) => return,
+                    };
                     let req = tonic::Request::new(req.clone());
                     client.conn.heartbeat_request(req).await.unwrap();
                 });
@@ -583,13 +1035,42 @@ impl StoreTransport for RpcTransport {
                 let peer = (self.node_addr)(to_id);
                 let pool = self.connections.clone();
                 tokio::task::spawn(async move {
-                    let mut client = pool.connection(peer).await;
+                    let mut client = match pool.connection(peer).await {
+                        Ok(client) => client,
+                        Err(_) => return,
+                    };
                     let req = tonic::Request::new(req.clone());
                     client.conn.heartbeat_reply(req).await.unwrap();
                 });
             },
         };
     }
+
+    async fn confirm_leadership(&self, to_id: u64, ballot: omnipaxos_core::ballot_leader_election::Ballot) -> bool {
+        let req = LeadershipReq {
+            ballot: Some(proto_from_ballot(ballot)),
+        };
+
+        let peer = (self.node_addr)(to_id);
+        // an unreachable node does not confirm
+        let reply = match self.connections.connection(peer).await {
+            Ok(mut client) => client.conn.confirm_leadership(Request::new(req)).await,
+            Err(_) => return false,
+        };
+        matches!(reply, Ok(reply) if reply.get_ref().confirmed)
+    }
+
+    async fn read_index(&self, to_id: u64) -> Result<(u32, u64), StoreError> {
+        let peer = (self.node_addr)(to_id);
+        let mut client = self.connections.connection(peer).await.map_err(|_| StoreError::NotLeader)?;
+        match client.conn.read_index(Request::new(ReadIndexReq {})).await {
+            Ok(reply) => {
+                let reply = reply.into_inner();
+                Ok((reply.config_id, reply.idx))
+            }
+            Err(_) => Err(StoreError::NotLeader),
+        }
+    }
 }
 
 /// RPC service.
@@ -614,22 +1095,64 @@ impl Rpc for RpcService {
         &self,
         request: Request<Query>,
     ) -> Result<Response<QueryResults>, tonic::Status> {
+        let timeout = timeout_from_request(&request);
         let query = request.into_inner();
         
         let server = self.server.clone();
-        let results = match server.query(query.sql).await {
+        let params = params_from_proto(query.params, query.named_params);
+        let consistency = consistency_from_proto(query.consistency, query.max_staleness_ms, query.min_index_config, query.min_index);
+        let request = query.request.map(request_id_from_proto);
+        let results = match server.query_with_params(query.sql, params, consistency, request, timeout).await {
             Ok(results) => results,
-            Err(e) => return Err(Status::internal(format!("{}", e))),
+            Err(e) => return Err(status_from_error(e)),
         };
 
-        let mut rows = vec![];
-        for row in results.rows {
-            rows.push(QueryRow {
-                values: row.values.clone(),
-            })
-        }
+        Ok(Response::new(proto_from_query_results(results)))
+    }
+
+    type ExecuteStreamStream = Pin<Box<dyn Stream<Item = Result<QueryResults, Status>> + Send + Sync>>;
+
+    async fn execute_stream(
+        &self,
+        request: Request<Query>,
+    ) -> Result<Response<Self::ExecuteStreamStream>, tonic::Status> {
+        let timeout = timeout_from_request(&request);
+        let query = request.into_inner();
+
+        let server = self.server.clone();
+        let params = params_from_proto(query.params, query.named_params);
+        let consistency = consistency_from_proto(query.consistency, query.max_staleness_ms, query.min_index_config, query.min_index);
+        let request = query.request.map(request_id_from_proto);
+        let stream = match server.query_stream(query.sql, params, consistency, request, timeout).await {
+            Ok(stream) => stream,
+            Err(e) => return Err(status_from_error(e)),
+        };
+
+        let stream = stream.map(|results| match results {
+            Ok(results) => Ok(proto_from_query_results(results)),
+            Err(e) => Err(status_from_error(e)),
+        });
+        Ok(Response::new(Box::pin(stream)))
+    }
+
+    async fn batch(
+        &self,
+        request: Request<BatchReq>,
+    ) -> Result<Response<BatchResults>, tonic::Status> {
+        let timeout = timeout_from_request(&request);
+        let batch = request.into_inner();
+
+        let server = self.server.clone();
+        let statements = batch.statements.into_iter().map(statement_from_proto).collect();
+        let request = batch.request.map(request_id_from_proto);
+        let results = match server.execute_batch(statements, request, timeout).await {
+            Ok(results) => results,
+            Err(e) => return Err(status_from_error(e)),
+        };
 
-        Ok(Response::new(QueryResults { rows }))
+        Ok(Response::new(BatchResults {
+            results: results.into_iter().map(proto_from_query_results).collect(),
+        }))
     }
 
     async fn prepare(&self, request: Request<PrepareReq>) -> Result<Response<Void>, tonic::Status> {
@@ -667,8 +1190,8 @@ impl Rpc for RpcService {
         let n = ballot_from_proto(msg.n.unwrap());
         let n_accepted = ballot_from_proto(msg.n_accepted.unwrap());
         
-        let sync_item: Option<SyncItem<StoreCommand,()>> = match msg.sync_item {
-            Some(si) => Some(sync_item_from_proto(si)),
+        let sync_item: Option<SyncItem<StoreCommand, SQLiteSnapshot>> = match msg.sync_item {
+            Some(si) => Some(sync_item_from_proto(si, &self.server).map_err(|e| Status::internal(format!("{}", e)))?),
             _ => None,
         };
         
@@ -708,7 +1231,7 @@ impl Rpc for RpcService {
 
         let n = ballot_from_proto(msg.n.unwrap());
         
-        let sync_item = sync_item_from_proto(msg.sync_item.unwrap());
+        let sync_item = sync_item_from_proto(msg.sync_item.unwrap(), &self.server).map_err(|e| Status::internal(format!("{}", e)))?;
         let sync_idx = msg.sync_idx;
 
         let decide_idx = msg.decide_idx;
@@ -982,6 +1505,50 @@ impl Rpc for RpcService {
         Ok(Response::new(Void {}))
     }
 
+    async fn prepare_request(&self, request: Request<PrepareRequestReq>) -> Result<Response<Void>, tonic::Status> {
+        let msg = request.into_inner();
+        let from = msg.from;
+        let to = msg.to;
+
+        let msg = Message {
+            from,
+            to,
+            msg: PaxosMsg::PrepareReq,
+        };
+
+        let server = self.server.clone();
+        server.recv_sp_msg(msg);
+        
+        Ok(Response::new(Void {}))
+    }
+
+    async fn snapshot_status(&self, request: Request<SnapshotStatusReq>) -> Result<Response<SnapshotAck>, tonic::Status> {
+        let msg = request.into_inner();
+
+        let server = self.server.clone();
+        let received = match server.snapshot_transfer_offset(msg.idx, msg.size, msg.checksum) {
+            Ok(received) => received,
+            Err(e) => return Err(Status::internal(format!("{}", e))),
+        };
+
+        Ok(Response::new(SnapshotAck { received }))
+    }
+
+    async fn install_snapshot(&self, request: Request<tonic::Streaming<SnapshotChunk>>) -> Result<Response<SnapshotAck>, tonic::Status> {
+        let mut stream = request.into_inner();
+
+        let server = self.server.clone();
+        let mut received = 0;
+        while let Some(chunk) = stream.message().await? {
+            received = match server.write_snapshot_chunk(chunk.idx, chunk.size, chunk.checksum, chunk.offset, &chunk.data, chunk.chunk_checksum) {
+                Ok(received) => received,
+                Err(e) => return Err(Status::data_loss(format!("{}", e))),
+            };
+        }
+
+        Ok(Response::new(SnapshotAck { received }))
+    }
+
     async fn heartbeat_request(&self, request: Request<HeartbeatRequestReq>) -> Result<Response<Void>, tonic::Status> {
         let msg = request.into_inner();
         let from = msg.from;
@@ -1031,4 +1598,24 @@ impl Rpc for RpcService {
         
         Ok(Response::new(Void {}))
     }
+
+    async fn read_index(&self, _request: Request<ReadIndexReq>) -> Result<Response<ReadIndexReply>, tonic::Status> {
+        let server = self.server.clone();
+        let (config_id, idx) = match server.read_index().await {
+            Ok(read_index) => read_index,
+            Err(e) => return Err(Status::unavailable(format!("{}", e))),
+        };
+
+        Ok(Response::new(ReadIndexReply { idx, config_id }))
+    }
+
+    async fn confirm_leadership(&self, request: Request<LeadershipReq>) -> Result<Response<LeadershipReply>, tonic::Status> {
+        let msg = request.into_inner();
+        let ballot = ballot_from_proto(msg.ballot.unwrap());
+
+        let server = self.server.clone();
+        let confirmed = server.confirm_leadership(ballot);
+
+        Ok(Response::new(LeadershipReply { confirmed }))
+    }
 }
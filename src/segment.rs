@@ -0,0 +1,274 @@
+//! ChiselStore segmented log.
+//!
+//! Log entries are appended to segment files named after the absolute index
+//! of their first entry, `{first_idx:020}.seg`. Every entry is framed with its
+//! length and CRC32 checksums of both the length and the entry, so that a torn
+//! write at the end of the last segment is truncated away at startup, while a
+//! damaged entry anywhere else is reported as corruption instead of being
+//! applied.
+
+use crate::errors::StoreError;
+use crate::rpc::proto;
+use crate::rpc::{proto_from_store_command, store_command_from_proto};
+use crate::wal::{encode_frame, read_frame, sync_parent_dir, Frame};
+use crate::StoreCommand;
+use prost::Message;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+const SEGMENT_EXTENSION: &str = "seg";
+
+/// A segment file.
+#[derive(Debug)]
+struct Segment {
+    /// Absolute index of the first entry.
+    first_idx: u64,
+    path: PathBuf,
+    /// Offset of each entry in the file.
+    offsets: Vec<u64>,
+    /// Size of the file in bytes.
+    size: u64,
+}
+
+impl Segment {
+    /// Absolute index one past the last entry.
+    fn end_idx(&self) -> u64 {
+        self.first_idx + self.offsets.len() as u64
+    }
+}
+
+/// Log entries persisted in a directory of segment files.
+#[derive(Debug)]
+pub(crate) struct SegmentedLog {
+    dir: PathBuf,
+    /// Size after which a new segment is started.
+    segment_size: u64,
+    /// Segments in index order, the last one is appended to.
+    segments: Vec<Segment>,
+    active: File,
+}
+
+impl SegmentedLog {
+    /// Opens the log in `dir` and returns it together with the entries from
+    /// absolute index `start_idx` onwards.
+    ///
+    /// A torn entry at the end of the last segment is truncated away, and
+    /// segments that end before `start_idx` are deleted, as their entries are
+    /// compacted. Any other damaged entry or missing segment is a
+    /// [`StoreError::CorruptLog`].
+    pub(crate) fn open<P: AsRef<Path>>(
+        dir: P,
+        segment_size: u64,
+        start_idx: u64,
+    ) -> Result<(Self, Vec<StoreCommand>), StoreError> {
+        let dir = dir.as_ref().to_path_buf();
+        std::fs::create_dir_all(&dir)?;
+
+        let mut first_idxs = vec![];
+        for entry in std::fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.extension().map_or(true, |ext| ext != SEGMENT_EXTENSION) {
+                continue;
+            }
+            let first_idx = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse::<u64>().ok())
+                .ok_or_else(|| StoreError::CorruptLog(format!("unexpected segment {}", path.display())))?;
+            first_idxs.push(first_idx);
+        }
+        first_idxs.sort_unstable();
+
+        let mut segments: Vec<Segment> = vec![];
+        let mut entries = vec![];
+        let count = first_idxs.len();
+        for (i, first_idx) in first_idxs.into_iter().enumerate() {
+            if let Some(prev) = segments.last() {
+                if prev.end_idx() != first_idx {
+                    return Err(StoreError::CorruptLog(format!(
+                        "entries {} to {} are missing from {}",
+                        prev.end_idx(),
+                        first_idx,
+                        dir.display()
+                    )));
+                }
+            }
+            let path = segment_path(&dir, first_idx);
+            let (segment, mut segment_entries) = read_segment(path, first_idx, i + 1 == count)?;
+            entries.append(&mut segment_entries);
+            segments.push(segment);
+        }
+
+        let end_idx = segments.last().map_or(start_idx, |segment| segment.end_idx());
+        if start_idx > end_idx {
+            // a snapshot compacted the log past its end before a restart
+            for segment in segments.drain(..) {
+                std::fs::remove_file(&segment.path)?;
+            }
+            entries.clear();
+        }
+        let first_idx = segments.first().map_or(start_idx, |segment| segment.first_idx);
+        let end_idx = segments.last().map_or(start_idx, |segment| segment.end_idx());
+        if start_idx < first_idx {
+            return Err(StoreError::CorruptLog(format!(
+                "{} holds entries {} to {}, but the log starts at {}",
+                dir.display(),
+                first_idx,
+                end_idx,
+                start_idx
+            )));
+        }
+        let entries = entries.split_off((start_idx - first_idx) as usize);
+
+        if segments.is_empty() {
+            segments.push(create_segment(&dir, start_idx)?);
+        }
+        let active = OpenOptions::new().append(true).open(&segments.last().unwrap().path)?;
+        let log = SegmentedLog {
+            dir,
+            segment_size,
+            segments,
+            active,
+        };
+        Ok((log, entries))
+    }
+
+    /// Durably appends `entries` to the end of the log.
+    pub(crate) fn append(&mut self, entries: &[StoreCommand]) -> Result<(), StoreError> {
+        for entry in entries {
+            if self.segments.last().unwrap().size >= self.segment_size {
+                self.active.sync_data()?;
+                let end_idx = self.segments.last().unwrap().end_idx();
+                self.segments.push(create_segment(&self.dir, end_idx)?);
+                self.active = OpenOptions::new().append(true).open(&self.segments.last().unwrap().path)?;
+            }
+            let frame = encode_frame(&proto_from_store_command(entry.clone()).encode_to_vec());
+            self.active.write_all(&frame)?;
+            let segment = self.segments.last_mut().unwrap();
+            segment.offsets.push(segment.size);
+            segment.size += frame.len() as u64;
+        }
+        self.active.sync_data()?;
+        Ok(())
+    }
+
+    /// Removes the entries from absolute index `idx` onwards.
+    pub(crate) fn truncate(&mut self, idx: u64) -> Result<(), StoreError> {
+        while self.segments.len() > 1 && self.segments.last().unwrap().first_idx >= idx {
+            let segment = self.segments.pop().unwrap();
+            std::fs::remove_file(&segment.path)?;
+        }
+        let segment = self.segments.last_mut().unwrap();
+        if idx < segment.first_idx {
+            // the log is truncated past its first segment, start over at `idx`
+            std::fs::remove_file(&segment.path)?;
+            *segment = create_segment(&self.dir, idx)?;
+        } else if idx < segment.end_idx() {
+            let offset = segment.offsets[(idx - segment.first_idx) as usize];
+            segment.offsets.truncate((idx - segment.first_idx) as usize);
+            segment.size = offset;
+            let file = OpenOptions::new().write(true).open(&segment.path)?;
+            file.set_len(offset)?;
+            file.sync_all()?;
+        }
+        self.active = OpenOptions::new().append(true).open(&segment.path)?;
+        Ok(())
+    }
+
+    /// Deletes the segments holding only entries before absolute index `idx`.
+    ///
+    /// Entries before `idx` in the remaining first segment are skipped when
+    /// the log is opened. A log trimmed past its end, as by a snapshot that
+    /// is installed, starts over with a new segment at `idx`.
+    pub(crate) fn trim(&mut self, idx: u64) -> Result<(), StoreError> {
+        while self.segments.len() > 1 && self.segments[1].first_idx <= idx {
+            let segment = self.segments.remove(0);
+            std::fs::remove_file(&segment.path)?;
+        }
+        if idx >= self.end_idx() {
+            let segment = self.segments.pop().unwrap();
+            std::fs::remove_file(&segment.path)?;
+            self.segments.push(create_segment(&self.dir, idx)?);
+            self.active = OpenOptions::new().append(true).open(&self.segments.last().unwrap().path)?;
+        }
+        Ok(())
+    }
+
+    /// Absolute index one past the last entry.
+    pub(crate) fn end_idx(&self) -> u64 {
+        self.segments.last().unwrap().end_idx()
+    }
+}
+
+fn segment_path(dir: &Path, first_idx: u64) -> PathBuf {
+    dir.join(format!("{:020}.{}", first_idx, SEGMENT_EXTENSION))
+}
+
+fn create_segment(dir: &Path, first_idx: u64) -> Result<Segment, StoreError> {
+    let path = segment_path(dir, first_idx);
+    File::create(&path)?.sync_all()?;
+    // make the new file itself durable
+    sync_parent_dir(&path)?;
+    Ok(Segment {
+        first_idx,
+        path,
+        offsets: vec![],
+        size: 0,
+    })
+}
+
+/// Reads the segment at `path`, truncating a torn tail if it is the `last` segment.
+fn read_segment(path: PathBuf, first_idx: u64, last: bool) -> Result<(Segment, Vec<StoreCommand>), StoreError> {
+    let mut file = OpenOptions::new().read(true).write(true).open(&path)?;
+    let mut buf = vec![];
+    file.read_to_end(&mut buf)?;
+
+    let mut offsets = vec![];
+    let mut entries = vec![];
+    let mut offset = 0;
+    while offset < buf.len() {
+        let rest = &buf[offset..];
+        let idx = first_idx + entries.len() as u64;
+        let damage = match read_frame(rest) {
+            Frame::Complete(payload, len) => {
+                let entry = proto::StoreCommand::decode(payload).map_err(|_| {
+                    StoreError::CorruptLog(format!("entry {} in {} cannot be decoded", idx, path.display()))
+                })?;
+                offsets.push(offset as u64);
+                entries.push(store_command_from_proto(entry));
+                offset += len;
+                continue;
+            }
+            // only a write cut short by the end of the file is torn
+            Frame::Incomplete if last => None,
+            Frame::Corrupt(len) if last && len == rest.len() => None,
+            Frame::Incomplete => Some("is incomplete"),
+            Frame::Corrupt(_) => Some("does not match its checksum"),
+            Frame::CorruptHeader => Some("has a length that does not match its checksum"),
+        };
+        match damage {
+            None => {
+                file.set_len(offset as u64)?;
+                file.sync_all()?;
+                break;
+            }
+            Some(damage) => {
+                return Err(StoreError::CorruptLog(format!(
+                    "entry {} in {} {}",
+                    idx,
+                    path.display(),
+                    damage
+                )))
+            }
+        }
+    }
+
+    let segment = Segment {
+        first_idx,
+        path,
+        offsets,
+        size: offset as u64,
+    };
+    Ok((segment, entries))
+}
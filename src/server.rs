@@ -1,20 +1,38 @@
 //! ChiselStore server module.
 
+use crate::config::{CompactionMode, CompactionPolicy, StorageMode, StoreConfig};
 use crate::errors::StoreError;
+use crate::exec;
+use crate::memdb;
+use crate::snapshot::{
+    capture_image, capture_memdb_image, remove_image, restore_image, restore_memdb_image, verify_image,
+    SQLiteSnapshot, SnapshotImage,
+};
+use crate::log_storage::LogStorage;
+use crate::rpc::{proto, proto_from_query_results, proto_from_statement, query_results_from_proto};
+use crate::util::log::log;
+use crate::value::{Params, Value};
 use async_notify::Notify;
 use async_trait::async_trait;
-use tokio::time::{sleep, Duration};
+use tokio::time::{sleep, timeout_at, Duration, Instant};
 use derivative::Derivative;
-use sqlite::{Connection, OpenFlags};
+use sqlite::{Connection, State};
 use std::collections::HashMap;
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::future::Future;
+use std::pin::Pin;
+use std::fs::File;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio_stream::wrappers::ReceiverStream;
 use omnipaxos_core::{
     ballot_leader_election::{BLEConfig, BallotLeaderElection, Ballot},
     ballot_leader_election::messages::BLEMessage,
     sequence_paxos::{SequencePaxos, SequencePaxosConfig},
-    storage::{Storage, Snapshot, StopSignEntry},
-    messages::Message,
+    storage::{Storage, StopSignEntry},
+    messages::{Message, PaxosMsg},
 };
 
 /// ChiselStore transport layer.
@@ -24,261 +42,817 @@ use omnipaxos_core::{
 #[async_trait]
 pub trait StoreTransport {
     /// Send a store command message `msg` to `to_id` node.
-    fn send_sp(&self, to_id: u64, msg: Message<StoreCommand, ()>);
+    fn send_sp(&self, to_id: u64, msg: Message<StoreCommand, SQLiteSnapshot>);
     fn send_ble(&self, to_id: u64, msg: BLEMessage);
+    /// Ask `to_id` node whether it has not promised a ballot other than `ballot`.
+    async fn confirm_leadership(&self, to_id: u64, ballot: Ballot) -> bool;
+    /// Ask leader `to_id` for the configuration and log index a linearizable
+    /// read has to wait for.
+    async fn read_index(&self, to_id: u64) -> Result<(u32, u64), StoreError>;
 }
 
 /// Store command.
 ///
-/// A store command is a SQL statement that is replicated in the Raft cluster.
+/// A store command is a batch of SQL statements that is replicated in the
+/// Raft cluster and applied in one transaction.
 #[derive(Clone, Debug)]
 pub struct StoreCommand {
     /// Unique ID of this command.
-    pub id: u64,
-    /// The SQL statement of this command.
+    pub id: CommandId,
+    /// Client request the command carries out, if the client identified it.
+    pub request: Option<RequestId>,
+    /// The SQL statements of this command.
+    pub statements: Vec<Statement>,
+}
+
+/// ID of a store command, unique across the cluster.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct CommandId {
+    /// ID of the node the command was proposed at.
+    pub node_id: u64,
+    /// Start of the node the command was proposed in, as sequence numbers
+    /// begin anew on every start.
+    pub epoch: u64,
+    /// Sequence number of the command on its node.
+    pub seq: u64,
+}
+
+/// Identifies a client request, so that a retried request takes effect once.
+///
+/// The cluster keeps the results of the latest requests of every client,
+/// and answers a request it already applied with them.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct RequestId {
+    /// ID of the client, unique among the clients of the cluster.
+    pub client_id: String,
+    /// Sequence number of the request, increasing with every request of the client.
+    pub seq: u64,
+}
+
+/// SQL statement together with the parameters bound to it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Statement {
+    /// The SQL text, which may hold several statements.
     pub sql: String,
+    /// Parameters bound to the SQL statement.
+    pub params: Params,
+}
+
+impl Statement {
+    /// Creates a statement with `params` bound to `sql`.
+    pub fn new<S: Into<String>>(sql: S, params: Params) -> Self {
+        Self {
+            sql: sql.into(),
+            params,
+        }
+    }
 }
 
 // Used for handling async queries
 // #[derive(Clone, Debug)]
 #[derive(Debug)]
 pub struct QueryResultsHolder {
-    query_completion_notifiers: HashMap<u64, Arc<Notify>>,
-    results: HashMap<u64, Result<QueryResults, StoreError>>,
+    query_completion_notifiers: HashMap<CommandId, Arc<Notify>>,
+    results: HashMap<CommandId, Result<Vec<QueryResults>, StoreError>>,
+    proposals: HashMap<CommandId, Proposal>,
+}
+
+/// Whereabouts of a command proposed by this node that is not decided yet.
+#[derive(Debug)]
+struct Proposal {
+    /// Ballot of the leader the command was handed to, none while there is no leader.
+    ballot: Option<Ballot>,
+    /// Log index of the command, once it is in the log of this node.
+    idx: Option<u64>,
 }
 
 impl QueryResultsHolder {
-    pub fn insert_notifier(&mut self, id: u64, notifier: Arc<Notify>) {
+    /// Waits for the results of the command `id`, proposed to the leader
+    /// with `ballot`.
+    pub fn insert_notifier(&mut self, id: CommandId, notifier: Arc<Notify>, ballot: Option<Ballot>) {
         self.query_completion_notifiers.insert(id, notifier);
+        self.proposals.insert(id, Proposal { ballot, idx: None });
     }
 
-    pub fn push_result(&mut self, id: u64, result: Result<QueryResults, StoreError>) {
-        if let Some(completion) = self.query_completion_notifiers.remove(&(id as u64)) {
-            self.results.insert(id as u64, result);
+    pub fn push_result(&mut self, id: CommandId, result: Result<Vec<QueryResults>, StoreError>) {
+        self.proposals.remove(&id);
+        if let Some(completion) = self.query_completion_notifiers.remove(&id) {
+            self.results.insert(id, result);
             completion.notify();
         }
     }
 
-    pub fn remove_result(&mut self, id: &u64) -> Option<Result<QueryResults, StoreError>> {
+    pub fn remove_result(&mut self, id: &CommandId) -> Option<Result<Vec<QueryResults>, StoreError>> {
         self.results.remove(id)
     }
+
+    /// Forgets the command `id`, whose results are no longer waited for.
+    pub fn remove(&mut self, id: &CommandId) {
+        self.query_completion_notifiers.remove(id);
+        self.results.remove(id);
+        self.proposals.remove(id);
+    }
+
+    /// Records the log indices of proposed commands among `entries`,
+    /// appended to the log after index `from_idx`.
+    fn appended(&mut self, from_idx: u64, entries: &[StoreCommand]) {
+        if self.proposals.is_empty() {
+            return;
+        }
+        for (entry, idx) in entries.iter().zip(from_idx + 1..) {
+            if let Some(proposal) = self.proposals.get_mut(&entry.id) {
+                proposal.idx = Some(idx);
+            }
+        }
+    }
+
+    /// Hands the proposals that wait for a leader to the leader with `ballot`.
+    fn handed_to(&mut self, ballot: Ballot) {
+        for proposal in self.proposals.values_mut() {
+            proposal.ballot.get_or_insert(ballot);
+        }
+    }
+
+    /// Fails the proposals at indices in `decided`, where another command was
+    /// decided instead.
+    fn drop_overwritten(&mut self, decided: std::ops::Range<u64>) {
+        self.drop_where(|proposal| matches!(proposal.idx, Some(idx) if decided.contains(&idx)));
+    }
+
+    /// Fails the proposals handed to a leader older than `accepted_round`
+    /// that did not make it into the log of this node, which now follows
+    /// the log of the newer leader.
+    fn drop_unsynced(&mut self, accepted_round: Ballot) {
+        self.drop_where(|proposal| proposal.idx.is_none() && matches!(proposal.ballot, Some(ballot) if ballot < accepted_round));
+    }
+
+    /// Fails every proposal, as their configuration was stopped.
+    fn drop_all(&mut self) {
+        self.drop_where(|_| true);
+    }
+
+    fn drop_where<F: Fn(&Proposal) -> bool>(&mut self, dropped: F) {
+        let ids: Vec<CommandId> = self
+            .proposals
+            .iter()
+            .filter(|(_, proposal)| dropped(proposal))
+            .map(|(&id, _)| id)
+            .collect();
+        for id in ids {
+            self.push_result(id, Err(StoreError::ProposalDropped));
+        }
+    }
     
     fn default() -> Self {
         Self {
             query_completion_notifiers: HashMap::new(),
             results: HashMap::new(),
+            proposals: HashMap::new(),
         }
     }
 }
 
-/// Store configuration.
-#[derive(Debug)]
-struct StoreConfig {
-    /// Connection pool size.
-    conn_pool_size: usize,
-    query_results_holder: Arc<Mutex<QueryResultsHolder>>,
+/// Command whose results are waited for, forgotten by the
+/// [`QueryResultsHolder`] when the wait ends, even if it is cancelled.
+struct PendingCommand<'a> {
+    id: CommandId,
+    query_results_holder: &'a Mutex<QueryResultsHolder>,
 }
 
-#[derive(Clone)]
-#[derive(Derivative)]
-#[derivative(Debug)]
-struct SQLiteStore<S>
-where
-    S: Snapshot<StoreCommand>,
-{
-    /// Vector which contains all the replicated entries in-memory.
-    log: Vec<StoreCommand>,
-    /// Last promised round.
-    n_prom: Ballot,
-    /// Last accepted round.
-    acc_round: Ballot,
-    /// Length of the decided log.
-    ld: u64,
-
-    // TMP snapshots impl
+impl Drop for PendingCommand<'_> {
+    fn drop(&mut self) {
+        self.query_results_holder.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// Waits for `f` until `deadline`, if any.
+async fn until_deadline<F: Future>(deadline: Option<Instant>, f: F) -> Result<F::Output, StoreError> {
+    match deadline {
+        Some(deadline) => timeout_at(deadline, f).await.map_err(|_| StoreError::Timeout),
+        None => Ok(f.await),
+    }
+}
 
+/// Statistics about the replicated log, kept up to date by the store.
+#[derive(Debug, Default)]
+struct LogStats {
     /// Garbage collected index.
-    trimmed_idx: u64,
-    /// Stored snapshot
-    snapshot: Option<S>,
-    /// Stored StopSign
-    stopsign: Option<omnipaxos_core::storage::StopSignEntry>,
+    compacted_idx: AtomicU64,
+    /// Number of entries in the log.
+    entries: AtomicU64,
+    /// Size of the entries in the log in bytes.
+    bytes: AtomicU64,
+    /// Configuration and length of its log applied to the database.
+    applied: Mutex<(u32, u64)>,
+    /// Ballot promised by this node.
+    promise: Mutex<Ballot>,
+    /// Round in which this node last accepted entries.
+    accepted_round: Mutex<Ballot>,
+    /// Ballot of the leader last passed to SequencePaxos.
+    leader: Mutex<Ballot>,
+    /// Notified whenever entries are applied to the database or decided.
+    progress: tokio::sync::Notify,
+}
+
+impl LogStats {
+    /// Length of the log, including compacted entries.
+    fn log_len(&self) -> u64 {
+        self.compacted_idx.load(Ordering::SeqCst) + self.entries.load(Ordering::SeqCst)
+    }
+
+    /// Configuration and length of its log applied to the database.
+    fn applied(&self) -> (u32, u64) {
+        *self.applied.lock().unwrap()
+    }
+}
+
+/// Size of an entry as accounted for by the compaction policy.
+fn entry_size(entry: &StoreCommand) -> u64 {
+    entry
+        .statements
+        .iter()
+        .map(|stmt| stmt.sql.len() as u64 + stmt.params.size())
+        .sum()
+}
+
+#[derive(Derivative)]
+#[derivative(Debug)]
+struct SQLiteStore {
+    /// Replicated log and Sequence Paxos state.
+    log: Box<dyn LogStorage>,
+    /// True if the state was recovered from durable log storage or has to
+    /// be recovered from the cluster.
+    recovered: bool,
 
     /// SQLite
     this_id: u64,
-    #[derivative(Debug = "ignore")]
-    conn_pool: Vec<Arc<Mutex<Connection>>>,
-    conn_idx: usize,
+    configuration_id: u32,
+    config: StoreConfig,
+    /// Length of the log applied to the database.
+    applied_idx: u64,
+    /// Snapshot stored in the log that the database does not reflect yet.
+    pending_snapshot: Option<SQLiteSnapshot>,
+    conn_pool: Arc<ConnectionPool>,
     query_results_holder: Arc<Mutex<QueryResultsHolder>>,
+    log_stats: Arc<LogStats>,
 }
 
-impl <S> SQLiteStore<S>
-where
-    S: Snapshot<StoreCommand>
-{
-    pub fn new(this_id: u64, config: StoreConfig) -> Self {
-        let mut conn_pool = vec![];
-        let conn_pool_size = config.conn_pool_size;
-        for _ in 0..conn_pool_size {
-            // FIXME: Let's use the 'memdb' VFS of SQLite, which allows concurrent threads
-            // accessing the same in-memory database.
-            let flags = OpenFlags::new()
-                .set_read_write()
-                .set_create()
-                .set_no_mutex();
-            let mut conn =
-                Connection::open_with_flags(format!("node{}.db", this_id), flags).unwrap();
-            conn.set_busy_timeout(5000).unwrap();
-            conn_pool.push(Arc::new(Mutex::new(conn)));
-        }
-        let conn_idx = 0;
-        SQLiteStore {
-            log: Vec::new(),
-            n_prom: Ballot::default(),
-            acc_round: Ballot::default(),
-            ld: 0,
-
-            trimmed_idx: 0,
-            snapshot: None,
-            stopsign: None,
+/// Connections to the SQLite database of a node.
+///
+/// The pool outlives configurations. The store applies decided entries on a
+/// connection of its own, so that it never waits for a read to release its
+/// connection, and the server serves local reads on the others, handed out
+/// round-robin. A streamed read opens a connection of its own, as it may
+/// last long.
+#[derive(Derivative)]
+#[derivative(Debug)]
+struct ConnectionPool {
+    this_id: u64,
+    config: StoreConfig,
+    #[derivative(Debug = "ignore")]
+    writer: Arc<Mutex<Connection>>,
+    #[derivative(Debug = "ignore")]
+    connections: Vec<Arc<Mutex<Connection>>>,
+    next: AtomicUsize,
+}
 
+impl ConnectionPool {
+    fn open(this_id: u64, config: &StoreConfig) -> Result<Self, StoreError> {
+        let writer = open_connection(this_id, config)?;
+        if config.storage_mode() == StorageMode::Disk {
+            // reads see a consistent state without keeping writes from committing
+            writer.execute("PRAGMA journal_mode=WAL")?;
+        }
+        let mut connections = vec![];
+        for _ in 0..config.conn_pool_size() {
+            connections.push(Arc::new(Mutex::new(open_connection(this_id, config)?)));
+        }
+        Ok(ConnectionPool {
             this_id,
+            config: config.clone(),
+            writer: Arc::new(Mutex::new(writer)),
+            connections,
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    /// Opens a connection outside the pool to stream a read on.
+    fn open_stream(&self) -> Result<Connection, StoreError> {
+        open_connection(self.this_id, &self.config)
+    }
+
+    /// Connection that decided entries are applied on.
+    fn writer(&self) -> Arc<Mutex<Connection>> {
+        self.writer.clone()
+    }
+
+    /// Connection to serve a read on.
+    fn get(&self) -> Arc<Mutex<Connection>> {
+        let idx = self.next.fetch_add(1, Ordering::SeqCst) % self.connections.len();
+        self.connections[idx].clone()
+    }
+}
+
+fn open_connection(this_id: u64, config: &StoreConfig) -> Result<Connection, StoreError> {
+    let flags = config.open_flags();
+    let mut conn = match config.storage_mode() {
+        StorageMode::Disk => Connection::open_with_flags(config.db_path(this_id), flags)?,
+        StorageMode::Memory => memdb::open(&config.memdb_name(this_id), flags)?,
+    };
+    conn.set_busy_timeout(BUSY_TIMEOUT_MS as usize)?;
+    Ok(conn)
+}
+
+impl SQLiteStore {
+    fn new(this_id: u64, configuration_id: u32, config: &StoreConfig, conn_pool: Arc<ConnectionPool>, query_results_holder: Arc<Mutex<QueryResultsHolder>>, log_stats: Arc<LogStats>) -> Result<Self, StoreError> {
+        let log = config.open_log_storage(this_id, configuration_id)?;
+        // without durable log storage nothing survives a restart, always catch up from the cluster
+        let recovered = !log.is_durable() || !log.is_empty();
+
+        let applied_idx = {
+            let conn = conn_pool.writer();
+            let conn = conn.lock().unwrap();
+            create_meta_table(&conn)?;
+            create_dedup_table(&conn)?;
+            match read_applied_idx(&conn, configuration_id)? {
+                Some(applied_idx) => applied_idx,
+                // a database that predates the meta table reflects every decided
+                // entry and any snapshot installed before a restart
+                None => std::cmp::max(log.get_decided_idx(), log.get_snapshot().map(|s| s.idx()).unwrap_or(0)),
+            }
+        };
+        // a snapshot that failed to install before a restart is installed again
+        let pending_snapshot = log.get_snapshot().filter(|s| s.idx() > applied_idx);
+        let store = SQLiteStore {
+            log,
+            recovered,
+
+            this_id,
+            configuration_id,
+            config: config.clone(),
+            applied_idx,
+            pending_snapshot,
             conn_pool,
-            conn_idx,
 
-            query_results_holder: config.query_results_holder,
-        }
+            query_results_holder,
+            log_stats,
+        };
+        store.update_log_stats();
+        *store.log_stats.applied.lock().unwrap() = (configuration_id, applied_idx);
+        store.log_stats.progress.notify_waiters();
+        *store.log_stats.promise.lock().unwrap() = store.log.get_promise();
+        *store.log_stats.accepted_round.lock().unwrap() = store.log.get_accepted_round();
+        Ok(store)
     }
 
     pub fn get_connection(&mut self) -> Arc<Mutex<Connection>> {
-        let idx = self.conn_idx % self.conn_pool.len();
-        let conn = &self.conn_pool[idx];
-        self.conn_idx += 1;
-        conn.clone()
+        self.conn_pool.writer()
+    }
+
+    fn update_log_stats(&self) {
+        let bytes = self.log.get_suffix(0).iter().map(entry_size).sum();
+        self.log_stats.compacted_idx.store(self.log.get_compacted_idx(), Ordering::SeqCst);
+        self.log_stats.entries.store(self.log.get_log_len(), Ordering::SeqCst);
+        self.log_stats.bytes.store(bytes, Ordering::SeqCst);
+    }
+
+    fn snapshot_path(&self, idx: u64) -> PathBuf {
+        self.config.node_file_path(self.this_id, &format!("-config{}-snapshot{}.db", self.configuration_id, idx))
+    }
+
+    /// Applies decided `entries` to the database and hands the results to waiting queries.
+    ///
+    /// The entries and the new applied index are committed in one transaction,
+    /// so that an entry is never applied twice, even across a crash. An entry
+    /// that fails is rolled back on its own and its error is its result, while
+    /// a failure to commit rolls back all of the entries.
+    fn apply(&mut self, entries: &[StoreCommand]) -> Result<(), StoreError> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+        let applied_idx = self.applied_idx + entries.len() as u64;
+        let configuration_id = self.configuration_id;
+        let dedup_window = self.config.dedup_window();
+        let dedup_expiry = self.config.dedup_expiry();
+        let conn = self.get_connection();
+        let results = {
+            let mut conn = conn.lock().unwrap();
+            conn.execute("BEGIN IMMEDIATE")?;
+            let results: Vec<_> = entries
+                .iter()
+                .zip(self.applied_idx + 1..)
+                .map(|(q, idx)| {
+                    apply_entry(&mut conn, q, dedup_window, dedup_expiry).map(|mut results| {
+                        for r in results.iter_mut() {
+                            r.config_id = configuration_id;
+                            r.applied_idx = idx;
+                        }
+                        results
+                    })
+                })
+                .collect();
+            let committed = write_applied_idx(&conn, self.configuration_id, applied_idx)
+                .and_then(|_| Ok(conn.execute("COMMIT")?));
+            if let Err(e) = committed {
+                let _ = conn.execute("ROLLBACK");
+                return Err(e);
+            }
+            results
+        };
+        self.applied_idx = applied_idx;
+        *self.log_stats.applied.lock().unwrap() = (configuration_id, applied_idx);
+        self.log_stats.progress.notify_waiters();
+
+        let mut query_results_holder = self.query_results_holder.lock().unwrap();
+        for (q, results) in entries.iter().zip(results) {
+            // only the node a command was proposed at waits for its results
+            if q.id.node_id == self.this_id {
+                query_results_holder.push_result(q.id, results);
+            }
+        }
+        query_results_holder.drop_overwritten(self.applied_idx - entries.len() as u64 + 1..applied_idx + 1);
+        Ok(())
+    }
+
+    /// Brings the database up to `snapshot` and, once the database reflects
+    /// exactly the snapshot, replaces its commands with a fresh image.
+    fn install_snapshot(&mut self, snapshot: SQLiteSnapshot) -> Result<SQLiteSnapshot, StoreError> {
+        let idx = snapshot.idx();
+        if idx > self.applied_idx {
+            if let Some(image) = &snapshot.image {
+                if image.idx > self.applied_idx {
+                    let conn = self.get_connection();
+                    let mut conn = conn.lock().unwrap();
+                    let configuration_id = self.configuration_id;
+                    let finish = |conn: &Connection| {
+                        create_meta_table(conn)?;
+                        create_dedup_table(conn)?;
+                        write_applied_idx(conn, configuration_id, image.idx)
+                    };
+                    match self.config.storage_mode() {
+                        StorageMode::Disk => restore_image(&conn, image, finish)?,
+                        StorageMode::Memory => restore_memdb_image(&mut conn, image, finish)?,
+                    }
+                    self.applied_idx = image.idx;
+                    *self.log_stats.applied.lock().unwrap() = (configuration_id, image.idx);
+                    self.log_stats.progress.notify_waiters();
+                }
+            }
+            let base = snapshot.image.as_ref().map(|image| image.idx).unwrap_or(0);
+            let entries = snapshot.entries[(self.applied_idx - base) as usize..].to_vec();
+            self.apply(&entries)?;
+        }
+        if self.applied_idx != idx {
+            // we are ahead of the snapshot, keep its commands
+            return Ok(snapshot);
+        }
+
+        let path = self.snapshot_path(idx);
+        let image = match snapshot.image {
+            Some(image) if snapshot.entries.is_empty() => {
+                if image.path != path {
+                    std::fs::rename(&image.path, &path)?;
+                }
+                SnapshotImage { path, ..image }
+            }
+            _ => {
+                let conn = self.get_connection();
+                let mut conn = conn.lock().unwrap();
+                match self.config.storage_mode() {
+                    StorageMode::Disk => capture_image(&conn, idx, &path)?,
+                    StorageMode::Memory => capture_memdb_image(&mut conn, idx, &path)?,
+                }
+            }
+        };
+        Ok(SQLiteSnapshot {
+            image: Some(image),
+            entries: vec![],
+        })
+    }
+
+    /// Installs `snapshot`, stores it in the log and removes the images that
+    /// are no longer referenced.
+    ///
+    /// A snapshot that fails to install is stored as it is and stays pending,
+    /// to be installed again before any further entry is applied.
+    fn store_snapshot(&mut self, snapshot: SQLiteSnapshot) -> Result<(), StoreError> {
+        let incoming = snapshot.image.clone();
+        let previous = self.log.get_snapshot().and_then(|s| s.image);
+        let (snapshot, installed) = match self.install_snapshot(snapshot.clone()) {
+            Ok(installed) => (installed, Ok(())),
+            Err(e) => (snapshot, Err(e)),
+        };
+        self.pending_snapshot = installed.is_err().then(|| snapshot.clone());
+        self.log.set_snapshot(snapshot.clone())?;
+
+        // remove images that are no longer referenced
+        let current = snapshot.image.as_ref().map(|image| image.path.clone());
+        for image in [previous, incoming].into_iter().flatten() {
+            if Some(&image.path) != current.as_ref() {
+                remove_image(&image.path);
+            }
+        }
+        installed
     }
 }
 
-fn query(conn: Arc<Mutex<Connection>>, sql: String) -> Result<QueryResults, StoreError> {
-    let conn = conn.lock().unwrap();
-    let mut rows = vec![];
-    conn.iterate(sql, |pairs| {
-        let mut row = QueryRow::new();
-        for &(_, value) in pairs.iter() {
-            row.values.push(value.unwrap().to_string());
+/// Runs the statements of `entry` in a savepoint, so that a failing statement
+/// rolls back the whole entry and leaves no partial changes behind.
+fn apply_entry(conn: &mut Connection, entry: &StoreCommand, dedup_window: u64, dedup_expiry: u64) -> Result<Vec<QueryResults>, StoreError> {
+    let checksum = statements_checksum(&entry.statements);
+    // a retried request is answered with the results it took effect with
+    if let Some(request) = &entry.request {
+        if let Some(results) = read_dedup_results(conn, request, checksum)? {
+            return Ok(results);
         }
-        rows.push(row);
-        true
+        if is_dedup_expired(conn, request, dedup_window)? {
+            return Err(StoreError::RequestExpired);
+        }
+    }
+    conn.execute("SAVEPOINT chisel_entry")?;
+    let results = entry
+        .statements
+        .iter()
+        .map(|stmt| exec::execute(conn, &stmt.sql, &stmt.params))
+        .collect::<Result<Vec<_>, _>>()
+        .and_then(|results| match &entry.request {
+            Some(request) => write_dedup_results(conn, request, checksum, &results, dedup_window, dedup_expiry).map(|_| results),
+            None => Ok(results),
+        });
+    if results.is_err() {
+        conn.execute("ROLLBACK TO chisel_entry")?;
+    }
+    conn.execute("RELEASE chisel_entry")?;
+    results
+}
+
+/// Table keeping the results of the latest requests of every client.
+const DEDUP_TABLE: &str = "_chisel_dedup";
+/// Table recording the latest request of every client that has results kept,
+/// numbered in the order the requests took effect.
+const DEDUP_CLIENTS_TABLE: &str = "_chisel_dedup_clients";
+
+fn create_dedup_table(conn: &Connection) -> Result<(), StoreError> {
+    conn.execute(format!(
+        "CREATE TABLE IF NOT EXISTS {} (client_id TEXT NOT NULL, seq INTEGER NOT NULL, checksum INTEGER NOT NULL, results BLOB NOT NULL, PRIMARY KEY (client_id, seq))",
+        DEDUP_TABLE
+    ))?;
+    conn.execute(format!(
+        "CREATE TABLE IF NOT EXISTS {} (client_id TEXT PRIMARY KEY, last_seen INTEGER NOT NULL)",
+        DEDUP_CLIENTS_TABLE
+    ))?;
+    conn.execute(format!(
+        "CREATE INDEX IF NOT EXISTS {0}_last_seen ON {0} (last_seen)",
+        DEDUP_CLIENTS_TABLE
+    ))?;
+    Ok(())
+}
+
+/// Checksum of `statements`, which tells a retried request apart from another
+/// request that reuses its ID.
+fn statements_checksum(statements: &[Statement]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    for stmt in statements {
+        hasher.update(&prost::Message::encode_length_delimited_to_vec(&proto_from_statement(stmt.clone())));
+    }
+    hasher.finalize()
+}
+
+fn read_dedup_results(conn: &Connection, request: &RequestId, checksum: u32) -> Result<Option<Vec<QueryResults>>, StoreError> {
+    let mut stmt = conn.prepare(format!("SELECT checksum, results FROM {} WHERE client_id = ? AND seq = ?", DEDUP_TABLE))?;
+    stmt.bind(1, request.client_id.as_str())?;
+    stmt.bind(2, request.seq as i64)?;
+    if let State::Done = stmt.next()? {
+        return Ok(None);
+    }
+    if stmt.read::<i64>(0)? != checksum as i64 {
+        return Err(StoreError::RequestMismatch);
+    }
+    let results = <proto::BatchResults as prost::Message>::decode(stmt.read::<Vec<u8>>(1)?.as_slice()).map_err(|e| sqlite::Error {
+        code: Some(sqlite3_sys::SQLITE_CORRUPT as isize),
+        message: Some(format!("Corrupted results of request {}/{}: {}", request.client_id, request.seq, e)),
     })?;
-    Ok(QueryResults { rows })
+    Ok(Some(results.results.into_iter().map(query_results_from_proto).collect()))
+}
+
+/// True if `request` is too old to be told apart from a request that took
+/// effect, its results having been dropped.
+fn is_dedup_expired(conn: &Connection, request: &RequestId, dedup_window: u64) -> Result<bool, StoreError> {
+    let mut stmt = conn.prepare(format!("SELECT COALESCE(MAX(seq), -1) FROM {} WHERE client_id = ?", DEDUP_TABLE))?;
+    stmt.bind(1, request.client_id.as_str())?;
+    stmt.next()?;
+    let latest = stmt.read::<i64>(0)?;
+    Ok(latest >= 0 && request.seq.saturating_add(dedup_window) <= latest as u64)
+}
+
+/// Records the `results` of `request`, dropping those of requests of the
+/// client that fell out of the window and those of clients that expired.
+fn write_dedup_results(conn: &Connection, request: &RequestId, checksum: u32, results: &[QueryResults], dedup_window: u64, dedup_expiry: u64) -> Result<(), StoreError> {
+    let encoded = prost::Message::encode_to_vec(&proto::BatchResults {
+        results: results.iter().cloned().map(proto_from_query_results).collect(),
+    });
+    let mut stmt = conn.prepare(format!("INSERT INTO {} (client_id, seq, checksum, results) VALUES (?, ?, ?, ?)", DEDUP_TABLE))?;
+    stmt.bind(1, request.client_id.as_str())?;
+    stmt.bind(2, request.seq as i64)?;
+    stmt.bind(3, checksum as i64)?;
+    stmt.bind(4, encoded.as_slice())?;
+    while let State::Row = stmt.next()? {}
+    let mut stmt = conn.prepare(format!(
+        "INSERT OR REPLACE INTO {0} (client_id, last_seen) VALUES (?, (SELECT COALESCE(MAX(last_seen), 0) + 1 FROM {0}))",
+        DEDUP_CLIENTS_TABLE
+    ))?;
+    stmt.bind(1, request.client_id.as_str())?;
+    while let State::Row = stmt.next()? {}
+    // clients that sent none of the latest requests expire
+    let mut stmt = conn.prepare(format!(
+        "DELETE FROM {0} WHERE client_id IN (SELECT client_id FROM {1} WHERE last_seen <= (SELECT MAX(last_seen) FROM {1}) - ?)",
+        DEDUP_TABLE, DEDUP_CLIENTS_TABLE
+    ))?;
+    stmt.bind(1, dedup_expiry as i64)?;
+    while let State::Row = stmt.next()? {}
+    let mut stmt = conn.prepare(format!(
+        "DELETE FROM {0} WHERE last_seen <= (SELECT MAX(last_seen) FROM {0}) - ?",
+        DEDUP_CLIENTS_TABLE
+    ))?;
+    stmt.bind(1, dedup_expiry as i64)?;
+    while let State::Row = stmt.next()? {}
+    let mut stmt = conn.prepare(format!(
+        "DELETE FROM {0} WHERE client_id = ?1 AND seq + ?2 <= (SELECT MAX(seq) FROM {0} WHERE client_id = ?1)",
+        DEDUP_TABLE
+    ))?;
+    stmt.bind(1, request.client_id.as_str())?;
+    stmt.bind(2, dedup_window as i64)?;
+    while let State::Row = stmt.next()? {}
+    Ok(())
+}
+
+/// Table recording the length of the log applied to the database, per configuration.
+const META_TABLE: &str = "_chisel_meta";
+
+fn create_meta_table(conn: &Connection) -> Result<(), StoreError> {
+    conn.execute(format!(
+        "CREATE TABLE IF NOT EXISTS {} (config_id INTEGER PRIMARY KEY, applied_idx INTEGER NOT NULL)",
+        META_TABLE
+    ))?;
+    Ok(())
 }
 
-impl<S> Storage<StoreCommand, S> for SQLiteStore<S>
-where
-    S: Snapshot<StoreCommand>,
-{
+fn read_applied_idx(conn: &Connection, configuration_id: u32) -> Result<Option<u64>, StoreError> {
+    let mut stmt = conn.prepare(format!("SELECT applied_idx FROM {} WHERE config_id = ?", META_TABLE))?;
+    stmt.bind(1, configuration_id as i64)?;
+    match stmt.next()? {
+        State::Row => Ok(Some(stmt.read::<i64>(0)? as u64)),
+        State::Done => Ok(None),
+    }
+}
+
+fn write_applied_idx(conn: &Connection, configuration_id: u32, applied_idx: u64) -> Result<(), StoreError> {
+    let mut stmt = conn.prepare(format!(
+        "INSERT OR REPLACE INTO {} (config_id, applied_idx) VALUES (?, ?)",
+        META_TABLE
+    ))?;
+    stmt.bind(1, configuration_id as i64)?;
+    stmt.bind(2, applied_idx as i64)?;
+    while let State::Row = stmt.next()? {}
+    Ok(())
+}
+
+impl Storage<StoreCommand, SQLiteSnapshot> for SQLiteStore {
     fn append_entry(&mut self, entry: StoreCommand) -> u64 {
-        self.log.push(entry);
-        self.get_log_len()
+        self.append_entries(vec![entry])
     }
 
     fn append_entries(&mut self, entries: Vec<StoreCommand>) -> u64 {
-        let mut e = entries;
-        self.log.append(&mut e);
+        let from_idx = self.log.get_compacted_idx() + self.log.get_log_len();
+        self.query_results_holder.lock().unwrap().appended(from_idx, &entries);
+        self.log_stats.entries.fetch_add(entries.len() as u64, Ordering::SeqCst);
+        self.log_stats.bytes.fetch_add(entries.iter().map(entry_size).sum(), Ordering::SeqCst);
+        self.log.append_entries(entries).expect("Failed to append to the log");
         self.get_log_len()
     }
 
     fn append_on_prefix(&mut self, from_idx: u64, entries: Vec<StoreCommand>) -> u64 {
-        self.log.truncate(from_idx as usize);
+        self.log.truncate(from_idx).expect("Failed to truncate the log");
+        self.update_log_stats();
         self.append_entries(entries)
     }
 
     fn set_promise(&mut self, n_prom: Ballot) {
-        self.n_prom = n_prom;
+        self.log.set_promise(n_prom).expect("Failed to store the promise");
+        *self.log_stats.promise.lock().unwrap() = n_prom;
     }
 
     fn set_decided_idx(&mut self, ld: u64) {
-        
-        let old_ld = self.ld;
-        let new_ld = ld;
+        // the entries past a snapshot are applied once the database reflects it
+        if let Some(snapshot) = self.pending_snapshot.take() {
+            if let Err(e) = self.store_snapshot(snapshot) {
+                log(format!("Failed to install snapshot: {}", e));
+                return;
+            }
+        }
 
-        self.ld = ld;
+        let old_ld = self.log.get_decided_idx();
+        let new_ld = ld;
+        let trimmed_idx = self.log.get_compacted_idx();
 
         
         let ss = self.get_stopsign();
-        if self.log.len() < (new_ld as usize) && !ss.is_none() { // stop sign decided, do nothing
+        if trimmed_idx + self.log.get_log_len() < new_ld && !ss.is_none() { // stop sign decided, do nothing
+            self.log.set_decided_idx(ld).expect("Failed to store the decided index");
+            self.log_stats.progress.notify_waiters();
             return;
         }
 
-        // commit decided transactions to DB
-        let queries_to_run = self.log[(old_ld as usize)..(new_ld as usize)].to_vec();
-        
-        for q in queries_to_run.iter() {
-            let conn = self.get_connection();
-            let results = query(conn, q.sql.clone());
-
-            let mut query_results_holder = self.query_results_holder.lock().unwrap();
-            query_results_holder.push_result(q.id, results);
+        // commit decided transactions to DB, skipping the ones a snapshot already brought in
+        let from = std::cmp::max(old_ld, self.applied_idx);
+        if from < new_ld {
+            let queries_to_run = self.log.get_entries(from - trimmed_idx, new_ld - trimmed_idx).to_vec();
+            // a failure, such as a busy database, may be transient
+            let mut applied = self.apply(&queries_to_run);
+            for _ in 1..APPLY_ATTEMPTS {
+                if applied.is_ok() {
+                    break;
+                }
+                applied = self.apply(&queries_to_run);
+            }
+            if let Err(e) = applied {
+                // the decided index is not recorded, so the entries are
+                // applied again once the next one is decided, which may take
+                // long, so the commands waiting for them fail instead of waiting
+                log(format!("Failed to apply decided entries: {}", e));
+                let mut query_results_holder = self.query_results_holder.lock().unwrap();
+                for q in queries_to_run.iter().filter(|q| q.id.node_id == self.this_id) {
+                    query_results_holder.push_result(q.id, Err(StoreError::ApplyFailed(e.to_string())));
+                }
+                return;
+            }
         }
+
+        // Only record the decided index once its entries are applied, so
+        // that a restart never skips an entry.
+        self.log.set_decided_idx(ld).expect("Failed to store the decided index");
+        self.log_stats.progress.notify_waiters();
     }
 
     fn get_decided_idx(&self) -> u64 {
-        self.ld
+        self.log.get_decided_idx()
     }
 
     fn set_accepted_round(&mut self, na: Ballot) {
-        self.acc_round = na;
+        self.log.set_accepted_round(na).expect("Failed to store the accepted round");
+        *self.log_stats.accepted_round.lock().unwrap() = na;
     }
 
     fn get_accepted_round(&self) -> Ballot {
-        self.acc_round
+        self.log.get_accepted_round()
     }
 
     fn get_entries(&self, from: u64, to: u64) -> &[StoreCommand] {
-        self.log.get(from as usize..to as usize).unwrap_or(&[])
+        self.log.get_entries(from, to)
     }
 
     fn get_log_len(&self) -> u64 {
-        self.log.len() as u64
+        self.log.get_log_len()
     }
 
     fn get_suffix(&self, from: u64) -> &[StoreCommand] {
-        match self.log.get(from as usize..) {
-            Some(s) => s,
-            None => &[],
-        }
+        self.log.get_suffix(from)
     }
 
     fn get_promise(&self) -> Ballot {
-        self.n_prom
+        self.log.get_promise()
     }
 
     fn set_stopsign(&mut self, s: StopSignEntry) {
-        self.stopsign = Some(s);
+        self.log.set_stopsign(s).expect("Failed to store the StopSign");
     }
     
     fn get_stopsign(&self) -> Option<StopSignEntry> {
-        self.stopsign.clone()
+        self.log.get_stopsign()
     }
     
-    // TEMP: Snapshot impl
     fn trim(&mut self, trimmed_idx: u64) {
-        self.log.drain(0..trimmed_idx as usize);
+        self.log.trim(trimmed_idx).expect("Failed to trim the log");
+        self.update_log_stats();
     }
 
     fn set_compacted_idx(&mut self, trimmed_idx: u64) {
-        self.trimmed_idx = trimmed_idx;
+        self.log.set_compacted_idx(trimmed_idx).expect("Failed to store the compacted index");
+        self.log_stats.compacted_idx.store(trimmed_idx, Ordering::SeqCst);
     }
 
     fn get_compacted_idx(&self) -> u64 {
-        self.trimmed_idx
+        self.log.get_compacted_idx()
     }
 
-    fn set_snapshot(&mut self, snapshot: S) {
-        self.snapshot = Some(snapshot);
+    fn set_snapshot(&mut self, snapshot: SQLiteSnapshot) {
+        if let Err(e) = self.store_snapshot(snapshot) {
+            log(format!("Failed to install snapshot: {}", e));
+        }
     }
 
-    fn get_snapshot(&self) -> Option<S> {
-        self.snapshot.clone()
+    fn get_snapshot(&self) -> Option<SQLiteSnapshot> {
+        self.log.get_snapshot()
     }
 }
 
@@ -286,42 +860,137 @@ where
 #[derivative(Debug)]
 pub struct StoreServer<T: StoreTransport + Send + Sync> {
     this_id: u64,
+    /// Start of this node, in microseconds since the Unix epoch.
+    epoch: u64,
     next_cmd_id: AtomicU64,
     #[derivative(Debug = "ignore")]
-    sequence_paxos: Arc<Mutex<SequencePaxos<StoreCommand, (), SQLiteStore<()>>>>,
+    sequence_paxos: Arc<Mutex<SequencePaxos<StoreCommand, SQLiteSnapshot, SQLiteStore>>>,
     #[derivative(Debug = "ignore")]
     ballot_leader_election: Arc<Mutex<BallotLeaderElection>>,
     transport: T,
+    /// Nodes of the current configuration other than this one.
+    peers: Mutex<Vec<u64>>,
+    config: StoreConfig,
+    /// Lock on the data directory, held for the lifetime of the server.
+    _data_dir_lock: File,
+    conn_pool: Arc<ConnectionPool>,
     query_results_holder: Arc<Mutex<QueryResultsHolder>>,
+    log_stats: Arc<LogStats>,
+    /// When this node last learned decided entries from the leader.
+    synced_at: Mutex<Option<Instant>>,
+    /// Ballot under which this node leads with a lease, and until when.
+    lease: Mutex<Option<(Ballot, Instant)>>,
+    /// Ballot of the leader this node granted a lease to, and until when.
+    lease_grant: Mutex<Option<(Ballot, Instant)>>,
+    /// Newly elected leader, held back while a lease granted to another leader runs.
+    pending_leader: Mutex<Option<Ballot>>,
+    /// Prepare messages of other leaders, held back while a lease granted to another leader runs.
+    #[derivative(Debug = "ignore")]
+    deferred_msgs: Mutex<Vec<Message<StoreCommand, SQLiteSnapshot>>>,
+    compaction_policy: Mutex<CompactionPolicy>,
+    last_compaction: Mutex<Instant>,
+    /// Locks serializing the chunks of each incoming snapshot image, by index and checksum.
+    snapshot_transfers: Mutex<HashMap<(u64, u32), Arc<Mutex<()>>>>,
     halt: Arc<Mutex<bool>>,
 }
 
+/// Consistency mode of a read-only query.
+///
+/// Statements that may write to the database are always replicated through
+/// the leader, whatever the consistency mode.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Consistency {
+    /// Strong consistency. Reads are linearizable, they are served like
+    /// [`Consistency::ReadIndex`] reads.
+    Strong,
+    /// Relaxed reads. Reads are performed on the local node, which relaxes
+    /// read consistency and allows stale reads.
+    RelaxedReads,
+    /// Reads are performed on the local node if it learned decided entries
+    /// from the leader within the given duration, and are linearizable
+    /// otherwise.
+    BoundedStaleness(Duration),
+    /// Linearizable reads that are served from the local database once it
+    /// reflects every entry decided before the read, which the leader
+    /// confirms with a quorum of nodes instead of appending to the log.
+    ReadIndex,
+    /// Reads are performed on the local node once its database reflects the
+    /// log of the given configuration up to the given index, such as the
+    /// [`QueryResults::config_id`] and [`QueryResults::applied_idx`] of an
+    /// earlier write, which makes that write visible to the read. A node in a
+    /// later configuration reflects the whole log of the earlier ones.
+    MinIndex(u32, u64),
+}
+
+impl Default for Consistency {
+    fn default() -> Self {
+        Consistency::Strong
+    }
+}
+
 /// Query row.
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct QueryRow {
     /// Column values of the row.
-    pub values: Vec<String>,
+    pub values: Vec<Value>,
 }
 
-impl QueryRow {
-    fn new() -> Self {
-        QueryRow { values: Vec::new() }
-    }
+/// Result set column.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Column {
+    /// Name of the column.
+    pub name: String,
+    /// Declared type of the table column the result column comes from, if any.
+    pub decl_type: Option<String>,
+    /// Table the result column comes from, if any.
+    pub table: Option<String>,
 }
 
+/// Streamed query results, in batches of rows.
+pub type QueryStream = ReceiverStream<Result<QueryResults, StoreError>>;
+
 /// Query results.
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct QueryResults {
+    /// Query result columns.
+    pub columns: Vec<Column>,
     /// Query result rows.
     pub rows: Vec<QueryRow>,
+    /// Number of rows inserted, updated or deleted by the statement.
+    pub rows_affected: u64,
+    /// Rowid of the last row inserted by the statement, if any.
+    pub last_insert_rowid: Option<i64>,
+    /// Length of the log the database reflected when the statement ran.
+    ///
+    /// Reads with [`Consistency::MinIndex`] of this index and
+    /// [`QueryResults::config_id`] on any node see the effects of the
+    /// statement. Indices restart with every configuration of the cluster.
+    pub applied_idx: u64,
+    /// Configuration of the cluster whose log [`QueryResults::applied_idx`]
+    /// refers to.
+    pub config_id: u32,
 }
 
 const HEARTBEAT_TIMEOUT: u64 = 10; // ticks until timeout
 const MESSAGE_LOOP_TIMEOUT_MS: u64 = 1; // How often to check for new outgoing messages
 const BLE_LOOP_TIMEOUT_MS: u64 = 100; // How often to check to do a BLE tick
+const STREAM_BATCH_ROWS: usize = 256; // Rows in a batch of streamed query results
+const STREAM_BUFFER_BATCHES: usize = 4; // Batches a streamed query runs ahead of its receiver
+const STREAM_MAX_DURATION_MS: u64 = 60000; // How long a streamed query may read for
+const BUSY_TIMEOUT_MS: u64 = 5000; // How long a connection waits for another one to release the database
+const APPLY_ATTEMPTS: usize = 3; // How often decided entries are applied before the commands waiting for them fail
+const CONFIRM_LEADERSHIP_TIMEOUT_MS: u64 = 1000; // How long a leader waits for a node to confirm it, without leases
 impl<T: StoreTransport + Send + Sync> StoreServer<T> {
     /// Start a new server as part of a ChiselStore cluster.
     pub fn start(this_id: u64, peers: Vec<u64>, transport: T) -> Result<Self, StoreError> {
+        Self::start_with_config(this_id, peers, transport, StoreConfig::default())
+    }
+
+    /// Start a new server as part of a ChiselStore cluster with the given store configuration.
+    pub fn start_with_config(this_id: u64, peers: Vec<u64>, transport: T, mut config: StoreConfig) -> Result<Self, StoreError> {
+        let data_dir_lock = config.lock_data_dir()?;
+        config.assign_memdb_instance();
+
         // sequence paxos
         let configuration_id = 1;
 
@@ -332,23 +1001,40 @@ impl<T: StoreTransport + Send + Sync> StoreServer<T> {
 
         let query_results_holder = Arc::new(Mutex::new(QueryResultsHolder::default()));
 
-        let sequence_paxos = Arc::new(Mutex::new(new_sequence_paxos(configuration_id, this_id, peers.clone(), query_results_holder.clone(), None)));
+        let log_stats = Arc::new(LogStats::default());
+        let conn_pool = Arc::new(ConnectionPool::open(this_id, &config)?);
+
+        let sequence_paxos = Arc::new(Mutex::new(new_sequence_paxos(configuration_id, this_id, peers.clone(), &config, conn_pool.clone(), query_results_holder.clone(), log_stats.clone(), None)?));
 
         // ballot leader election
         let mut ble_config = BLEConfig::default();
         ble_config.set_pid(this_id);
-        ble_config.set_peers(peers);
+        ble_config.set_peers(peers.clone());
         ble_config.set_hb_delay(HEARTBEAT_TIMEOUT);
 
         let ballot_leader_election = Arc::new(Mutex::new(BallotLeaderElection::with(ble_config)));
         
         Ok(StoreServer {
             this_id,
+            epoch: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_micros() as u64,
             next_cmd_id: AtomicU64::new(0),
             sequence_paxos,
             ballot_leader_election,
             transport,
+            peers: Mutex::new(peers),
+            compaction_policy: Mutex::new(config.compaction_policy()),
+            config,
+            _data_dir_lock: data_dir_lock,
+            conn_pool,
             query_results_holder,
+            log_stats,
+            synced_at: Mutex::new(None),
+            lease: Mutex::new(None),
+            lease_grant: Mutex::new(None),
+            pending_leader: Mutex::new(None),
+            deferred_msgs: Mutex::new(vec![]),
+            last_compaction: Mutex::new(Instant::now()),
+            snapshot_transfers: Mutex::new(HashMap::new()),
             halt: Arc::new(Mutex::new(false)),
         })
     }
@@ -362,6 +1048,15 @@ impl<T: StoreTransport + Send + Sync> StoreServer<T> {
                 break
             }
 
+            // deliver the messages held back by a lease that ran out
+            let lease_granted = matches!(*self.lease_grant.lock().unwrap(), Some((_, until)) if Instant::now() < until);
+            if !lease_granted {
+                let deferred_msgs = std::mem::take(&mut *self.deferred_msgs.lock().unwrap());
+                for msg in deferred_msgs {
+                    self.recv_sp_msg(msg);
+                }
+            }
+
             let mut sequence_paxos = self.sequence_paxos.lock().unwrap();
             let mut ballot_leader_election = self.ballot_leader_election.lock().unwrap();
 
@@ -385,6 +1080,8 @@ impl<T: StoreTransport + Send + Sync> StoreServer<T> {
                     let final_entry = final_entry.unwrap();
                     match final_entry {
                         omnipaxos_core::util::LogEntry::StopSign(ss) => {
+                            // entries not decided by the stopped configuration never will be
+                            self.query_results_holder.lock().unwrap().drop_all();
                             if !ss.nodes.contains(&self.this_id) { // node not part of new configuration
                                 return;
                             }
@@ -396,10 +1093,14 @@ impl<T: StoreTransport + Send + Sync> StoreServer<T> {
                             nodes.remove(this_idx);
                             
                             let peers = nodes;
+                            *self.peers.lock().unwrap() = peers.clone();
 
+                            let conn_pool = self.conn_pool.clone();
                             let query_results_holder = self.query_results_holder.clone();
+                            let log_stats = self.log_stats.clone();
                             
-                            *sequence_paxos = new_sequence_paxos(configuration_id, self.this_id, peers, query_results_holder, ballot_leader_election.get_leader());
+                            *sequence_paxos = new_sequence_paxos(configuration_id, self.this_id, peers, &self.config, conn_pool, query_results_holder, log_stats, ballot_leader_election.get_leader())
+                                .expect("Failed to start new configuration");
                         },
                         _ => panic!("Unexpected log entry"),
                     };
@@ -410,49 +1111,496 @@ impl<T: StoreTransport + Send + Sync> StoreServer<T> {
     
     /// Run the blocking event loop.
     pub async fn run_ble_loop(&self) {
+        // lease renewal in flight, which runs alongside the ticks
+        let mut renewal: Option<Pin<Box<dyn Future<Output = ()> + Send + '_>>> = None;
         loop {
-            sleep(Duration::from_millis(BLE_LOOP_TIMEOUT_MS)).await;
+            let tick = sleep(Duration::from_millis(BLE_LOOP_TIMEOUT_MS));
+            tokio::pin!(tick);
+            if let Some(pending) = renewal.as_mut() {
+                let renewed = matches!(futures::future::select(pending, &mut tick).await, futures::future::Either::Left(_));
+                if renewed {
+                    renewal = None;
+                }
+            }
+            tick.await;
 
             if *self.halt.lock().unwrap() {
                 break
             }
 
-            let mut sequence_paxos = self.sequence_paxos.lock().unwrap();
-            let mut ballot_leader_election = self.ballot_leader_election.lock().unwrap();
+            let renew_lease = {
+                let mut sequence_paxos = self.sequence_paxos.lock().unwrap();
+                let mut ballot_leader_election = self.ballot_leader_election.lock().unwrap();
 
-            if let Some(leader) = ballot_leader_election.tick() {
-                // a new leader is elected, pass it to SequencePaxos.
-                sequence_paxos.handle_leader(leader);
+                let mut pending_leader = self.pending_leader.lock().unwrap();
+                if let Some(leader) = ballot_leader_election.tick() {
+                    *pending_leader = Some(leader);
+                }
+                if let Some(leader) = *pending_leader {
+                    if !self.granted_lease_to_other(leader) {
+                        // a new leader is elected, pass it to SequencePaxos.
+                        sequence_paxos.handle_leader(leader);
+                        *self.log_stats.leader.lock().unwrap() = leader;
+                        self.query_results_holder.lock().unwrap().handed_to(leader);
+                        *pending_leader = None;
+                        *self.lease.lock().unwrap() = None;
+                    }
+                }
+
+                self.maybe_compact(&mut sequence_paxos);
+
+                self.config.leader_lease().is_some() && sequence_paxos.get_current_leader() == self.this_id
+            };
+            if renew_lease && renewal.is_none() {
+                // an unconfirmed leader is told so by the next read
+                renewal = Some(Box::pin(async move {
+                    let _ = self.confirm_quorum().await;
+                }));
             }
         }
     }
 
+    /// Compact the log if the compaction policy says so.
+    ///
+    /// Only the leader drives compaction, the followers compact when they
+    /// receive the resulting compaction messages.
+    fn maybe_compact(&self, sequence_paxos: &mut SequencePaxos<StoreCommand, SQLiteSnapshot, SQLiteStore>) {
+        let policy = self.compaction_policy.lock().unwrap().clone();
+        let mut last_compaction = self.last_compaction.lock().unwrap();
+        let entries = self.log_stats.entries.load(Ordering::SeqCst);
+        let bytes = self.log_stats.bytes.load(Ordering::SeqCst);
+        if !policy.is_due(entries, bytes, last_compaction.elapsed()) {
+            return;
+        }
+        if sequence_paxos.get_current_leader() != self.this_id {
+            return;
+        }
+
+        let result = match policy.mode {
+            // up to the index decided by every replica
+            CompactionMode::Trim => sequence_paxos.trim(None),
+            // up to the index decided by a quorum
+            CompactionMode::Snapshot => sequence_paxos.snapshot(None, false),
+        };
+        // a failed compaction, e.g. because a replica lags behind, is retried on the next tick
+        if result.is_ok() {
+            *last_compaction = Instant::now();
+        }
+    }
+
+    /// Set the policy used to compact the replicated log.
+    pub fn set_compaction_policy(&self, policy: CompactionPolicy) {
+        *self.compaction_policy.lock().unwrap() = policy;
+    }
+
+    /// Index up to which the replicated log has been compacted.
+    pub fn get_compacted_idx(&self) -> u64 {
+        self.log_stats.compacted_idx.load(Ordering::SeqCst)
+    }
+
     /// Execute a SQL statement on the ChiselStore cluster.
+    ///
+    /// Fails with [`StoreError::Timeout`] if the statement did not complete
+    /// within `timeout`, if any.
     pub async fn query<S: AsRef<str>>(
         &self,
         stmt: S,
+        consistency: Consistency,
+        timeout: Option<Duration>,
     ) -> Result<QueryResults, StoreError> {
-        let results = {
-            let (notify, id) = {
-                let id = self.next_cmd_id.fetch_add(1, Ordering::SeqCst);
-                let cmd = StoreCommand {
-                    id: id,
-                    sql: stmt.as_ref().to_string(),
+        self.query_with_params(stmt, Params::None, consistency, None, timeout).await
+    }
+
+    /// Execute a SQL statement with bound parameters on the ChiselStore cluster.
+    ///
+    /// The statement is prepared and `params` are bound to it, so they never
+    /// have to be interpolated into the SQL text. Read-only statements are
+    /// served from the database of this node as `consistency` allows,
+    /// without appending to the replicated log. Any other statement is
+    /// replicated, once per `request` if the client identified it.
+    pub async fn query_with_params<S: AsRef<str>>(
+        &self,
+        stmt: S,
+        params: Params,
+        consistency: Consistency,
+        request: Option<RequestId>,
+        timeout: Option<Duration>,
+    ) -> Result<QueryResults, StoreError> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let stmt = stmt.as_ref().to_string();
+        if !self.is_read_only(&stmt).await? {
+            let mut results = self.replicate(vec![Statement::new(stmt, params)], request, deadline).await?;
+            return Ok(results.pop().unwrap());
+        }
+        until_deadline(deadline, self.wait_for_read(consistency)).await??;
+        self.read_local(stmt, params).await
+    }
+
+    /// Execute a SQL statement with bound parameters on the ChiselStore
+    /// cluster, streaming its results.
+    ///
+    /// Read-only statements are served like [`StoreServer::query_with_params`]
+    /// serves them, and their rows are streamed in batches as SQLite produces
+    /// them. Producing rows pauses while the receiver lags behind, and a
+    /// stream that lasts longer than a minute, or with [`StorageMode::Memory`]
+    /// longer than a tick of the leader election, ends with
+    /// [`StoreError::Timeout`]. Other statements are replicated and their
+    /// results streamed once applied.
+    ///
+    /// The first batch carries the result columns, the last batch the rows
+    /// changed and the last inserted rowid.
+    pub async fn query_stream<S: AsRef<str>>(
+        &self,
+        stmt: S,
+        params: Params,
+        consistency: Consistency,
+        request: Option<RequestId>,
+        timeout: Option<Duration>,
+    ) -> Result<QueryStream, StoreError> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let stmt = stmt.as_ref().to_string();
+        let (tx, rx) = tokio::sync::mpsc::channel(STREAM_BUFFER_BATCHES);
+        if !self.is_read_only(&stmt).await? {
+            let mut results = self.replicate(vec![Statement::new(stmt, params)], request, deadline).await?;
+            let batches = into_batches(results.pop().unwrap());
+            tokio::task::spawn(async move {
+                for batch in batches {
+                    if tx.send(Ok(batch)).await.is_err() {
+                        break;
+                    }
+                }
+            });
+            return Ok(ReceiverStream::new(rx));
+        }
+        until_deadline(deadline, self.wait_for_read(consistency)).await??;
+        let conn_pool = self.conn_pool.clone();
+        let (config_id, applied_idx) = self.log_stats.applied();
+        let expires = Instant::now() + max_stream_duration(self.config.storage_mode());
+        let runtime = tokio::runtime::Handle::current();
+        tokio::task::spawn_blocking(move || {
+            let mut columns_sent = false;
+            let mut closed = false;
+            let mut expired = false;
+            let mut rows = vec![];
+            let results = {
+                let mut conn = match conn_pool.open_stream() {
+                    Ok(conn) => conn,
+                    Err(e) => {
+                        let _ = tx.blocking_send(Err(e));
+                        return;
+                    }
                 };
-                
-                let notify = Arc::new(Notify::new());
+                // every statement reads the same state of the database
+                if let Err(e) = conn.execute("BEGIN") {
+                    let _ = tx.blocking_send(Err(e.into()));
+                    return;
+                }
+                let results = exec::stream_read_only(&mut conn, &stmt, &params, &mut |columns, row| {
+                    if Instant::now() >= expires {
+                        expired = true;
+                        return false;
+                    }
+                    rows.push(row);
+                    if rows.len() < STREAM_BATCH_ROWS {
+                        return true;
+                    }
+                    let batch = QueryResults {
+                        columns: if columns_sent { vec![] } else { columns.to_vec() },
+                        rows: std::mem::take(&mut rows),
+                        rows_affected: 0,
+                        last_insert_rowid: None,
+                        applied_idx,
+                        config_id,
+                    };
+                    columns_sent = true;
+                    // waits for the receiver to make room, as long as the stream may last
+                    match runtime.block_on(timeout_at(expires, tx.send(Ok(batch)))) {
+                        Ok(Ok(())) => true,
+                        Ok(Err(_)) => {
+                            closed = true;
+                            false
+                        }
+                        Err(_) => {
+                            expired = true;
+                            false
+                        }
+                    }
+                });
+                let _ = conn.execute("ROLLBACK");
+                results
+            };
+            if closed {
+                return;
+            }
+            let results = match results {
+                Ok(_) if expired => Err(StoreError::Timeout),
+                Ok(mut results) => {
+                    if columns_sent {
+                        results.columns = vec![];
+                    }
+                    results.rows = rows;
+                    results.applied_idx = applied_idx;
+                    results.config_id = config_id;
+                    Ok(results)
+                }
+                Err(e) => Err(e),
+            };
+            let _ = tx.blocking_send(results);
+        });
+        Ok(ReceiverStream::new(rx))
+    }
 
-                let mut query_results_holder = self.query_results_holder.lock().unwrap();
-                query_results_holder.insert_notifier(id, notify.clone());
-                
-                let mut sequence_paxos = self.sequence_paxos.lock().unwrap();
-                sequence_paxos.append(cmd).expect("Failed to append");
-                
-                (notify, id)
+    /// Waits until the database of this node can serve a read with `consistency`.
+    async fn wait_for_read(&self, consistency: Consistency) -> Result<(), StoreError> {
+        match consistency {
+            Consistency::RelaxedReads => {}
+            Consistency::BoundedStaleness(bound)
+                if matches!(self.staleness(), Some(staleness) if staleness <= bound) => {}
+            Consistency::MinIndex(config_id, idx) => self.wait_applied(config_id, idx).await,
+            Consistency::Strong | Consistency::ReadIndex | Consistency::BoundedStaleness(_) => {
+                let (config_id, read_idx) = self.linearizable_read_index().await?;
+                self.wait_applied(config_id, read_idx).await;
+            }
+        }
+        Ok(())
+    }
+
+    /// True if `stmt` only reads the database and can be served by this node.
+    async fn is_read_only(&self, stmt: &str) -> Result<bool, StoreError> {
+        let stmt = stmt.to_string();
+        self.with_connection(move |conn| Ok(exec::is_read_only(conn, &stmt))).await
+    }
+
+    /// Runs `f` on a pooled connection, on a thread where waiting for the
+    /// connection does not hold up other tasks.
+    async fn with_connection<F, R>(&self, f: F) -> Result<R, StoreError>
+    where
+        F: FnOnce(&mut Connection) -> Result<R, StoreError> + Send + 'static,
+        R: Send + 'static,
+    {
+        let conn = self.conn_pool.get();
+        tokio::task::spawn_blocking(move || f(&mut *conn.lock().unwrap()))
+            .await
+            .map_err(std::io::Error::from)?
+    }
+
+    /// Configuration and log index up to which the database has to be
+    /// applied to serve a linearizable read, obtained from the leader.
+    async fn linearizable_read_index(&self) -> Result<(u32, u64), StoreError> {
+        let leader = self.get_current_leader();
+        if leader == self.this_id {
+            self.read_index().await
+        } else if leader != 0 {
+            self.transport.read_index(leader).await
+        } else {
+            Err(StoreError::NotLeader)
+        }
+    }
+
+    /// Runs a read-only statement against the database of this node.
+    async fn read_local(&self, stmt: String, params: Params) -> Result<QueryResults, StoreError> {
+        let log_stats = self.log_stats.clone();
+        self.with_connection(move |conn| {
+            let (config_id, applied_idx) = log_stats.applied();
+            // every statement reads the same state of the database
+            conn.execute("BEGIN")?;
+            let results = exec::execute_read_only(conn, &stmt, &params);
+            conn.execute("ROLLBACK")?;
+            let mut results = results?;
+            results.applied_idx = applied_idx;
+            results.config_id = config_id;
+            Ok(results)
+        })
+        .await
+    }
+
+    /// Configuration and log index up to which the database has to be
+    /// applied to serve a linearizable read, called on the leader.
+    ///
+    /// The log of a leader that synchronized with a quorum of nodes includes
+    /// every decided entry, and a quorum of nodes confirms that no other
+    /// leader has been promised in the meantime. The read waits for the
+    /// leader to decide its log, and then reads up to its decided index.
+    pub async fn read_index(&self) -> Result<(u32, u64), StoreError> {
+        let leased = {
+            let sequence_paxos = self.sequence_paxos.lock().unwrap();
+            if sequence_paxos.get_current_leader() == self.this_id && self.holds_lease() {
+                Some((*self.log_stats.promise.lock().unwrap(), self.log_stats.log_len()))
+            } else {
+                None
+            }
+        };
+        let (ballot, log_len) = match leased {
+            Some(leased) => leased,
+            None => self.confirm_quorum().await?,
+        };
+        self.wait_decided(ballot, log_len).await
+    }
+
+    /// Waits until this node, leading in `ballot`, decided its log up to
+    /// `idx`, and returns its configuration and decided index.
+    ///
+    /// The entries of a synchronized leader are decided unless it loses its
+    /// leadership, which fails the wait.
+    async fn wait_decided(&self, ballot: Ballot, idx: u64) -> Result<(u32, u64), StoreError> {
+        loop {
+            let progress = self.log_stats.progress.notified();
+            {
+                let sequence_paxos = self.sequence_paxos.lock().unwrap();
+                if sequence_paxos.get_current_leader() != self.this_id || *self.log_stats.promise.lock().unwrap() != ballot {
+                    return Err(StoreError::NotLeader);
+                }
+                let decided_idx = sequence_paxos.get_decided_idx();
+                if decided_idx >= idx {
+                    let (config_id, _) = self.log_stats.applied();
+                    return Ok((config_id, decided_idx));
+                }
+            }
+            // losing the leadership makes no progress, so check for it now and then
+            let _ = tokio::time::timeout(Duration::from_millis(BLE_LOOP_TIMEOUT_MS), progress).await;
+        }
+    }
+
+    /// Has a quorum of nodes confirm the leadership of this node, and returns
+    /// its ballot and the length of its log. Renews the lease, if leases are
+    /// enabled.
+    async fn confirm_quorum(&self) -> Result<(Ballot, u64), StoreError> {
+        let start = Instant::now();
+        let (ballot, log_len, peers) = {
+            let sequence_paxos = self.sequence_paxos.lock().unwrap();
+            if sequence_paxos.get_current_leader() != self.this_id {
+                return Err(StoreError::NotLeader);
+            }
+            let ballot = *self.log_stats.promise.lock().unwrap();
+            // until the leader accepts in its own round, its log may lack decided entries
+            if *self.log_stats.accepted_round.lock().unwrap() != ballot {
+                return Err(StoreError::NotLeader);
+            }
+            (ballot, self.log_stats.log_len(), self.peers.lock().unwrap().clone())
+        };
+        // a confirmation arriving after the lease it renews ran out is of no use
+        let wait = match self.config.leader_lease() {
+            Some(lease) => lease.leader_duration(),
+            None => Duration::from_millis(CONFIRM_LEADERSHIP_TIMEOUT_MS),
+        };
+        let replies = futures::future::join_all(
+            peers.iter().map(|&peer| tokio::time::timeout(wait, self.transport.confirm_leadership(peer, ballot))),
+        )
+        .await;
+        let confirmed = replies.into_iter().filter(|reply| matches!(reply, Ok(true))).count();
+        // this node counts towards the quorum
+        if confirmed < (peers.len() + 1) / 2 {
+            return Err(StoreError::NotLeader);
+        }
+        if let Some(lease) = self.config.leader_lease() {
+            // the nodes granted their leases after `start` by their own clocks
+            *self.lease.lock().unwrap() = Some((ballot, start + lease.leader_duration()));
+        }
+        Ok((ballot, log_len))
+    }
+
+    /// Confirms the leadership of `ballot` to the leader serving a read.
+    ///
+    /// If leases are enabled, this grants the leader a lease.
+    pub fn confirm_leadership(&self, ballot: Ballot) -> bool {
+        let _sequence_paxos = self.sequence_paxos.lock().unwrap();
+        if *self.log_stats.promise.lock().unwrap() != ballot {
+            return false;
+        }
+        if let Some(lease) = self.config.leader_lease() {
+            *self.lease_grant.lock().unwrap() = Some((ballot, Instant::now() + lease.duration));
+        }
+        true
+    }
+
+    /// True if this node leads with a valid lease.
+    fn holds_lease(&self) -> bool {
+        let promise = *self.log_stats.promise.lock().unwrap();
+        matches!(*self.lease.lock().unwrap(), Some((ballot, until)) if ballot == promise && Instant::now() < until)
+    }
+
+    /// True if this node granted a lease that is still running to a leader other than `ballot`.
+    fn granted_lease_to_other(&self, ballot: Ballot) -> bool {
+        matches!(*self.lease_grant.lock().unwrap(), Some((granted, until)) if granted != ballot && Instant::now() < until)
+    }
+
+    /// Waits until the database of this node reflects the log of
+    /// configuration `config_id` up to `idx`, or a later configuration.
+    async fn wait_applied(&self, config_id: u32, idx: u64) {
+        loop {
+            let progress = self.log_stats.progress.notified();
+            if self.log_stats.applied() >= (config_id, idx) {
+                return;
+            }
+            progress.await;
+        }
+    }
+
+    /// How far the database of this node may lag behind the leader, if known.
+    fn staleness(&self) -> Option<Duration> {
+        if self.get_current_leader() == self.this_id {
+            return Some(Duration::ZERO);
+        }
+        self.synced_at.lock().unwrap().map(|synced_at| synced_at.elapsed())
+    }
+
+    /// Execute a batch of SQL statements on the ChiselStore cluster.
+    ///
+    /// The statements are replicated as one command and applied in one
+    /// transaction. The results are returned per statement; if any statement
+    /// fails, none of them take effect and its error is returned. This takes
+    /// the place of `BEGIN` and `COMMIT`, which statements may not contain.
+    ///
+    /// A batch identified by `request` takes effect once, however often it
+    /// is retried. Fails with [`StoreError::Timeout`] if the batch was not
+    /// applied within `timeout`, if any.
+    pub async fn execute_batch(&self, statements: Vec<Statement>, request: Option<RequestId>, timeout: Option<Duration>) -> Result<Vec<QueryResults>, StoreError> {
+        self.replicate(statements, request, timeout.map(|timeout| Instant::now() + timeout)).await
+    }
+
+    /// Replicates `statements` as one command and waits until `deadline`,
+    /// if any, for their results.
+    async fn replicate(&self, statements: Vec<Statement>, request: Option<RequestId>, deadline: Option<Instant>) -> Result<Vec<QueryResults>, StoreError> {
+        if statements.is_empty() {
+            return Ok(vec![]);
+        }
+        let results = {
+            let id = CommandId {
+                node_id: self.this_id,
+                epoch: self.epoch,
+                seq: self.next_cmd_id.fetch_add(1, Ordering::SeqCst),
+            };
+            let cmd = StoreCommand {
+                id: id,
+                request,
+                statements,
+            };
+            
+            let notify = Arc::new(Notify::new());
+            let _pending = PendingCommand {
+                id,
+                query_results_holder: &self.query_results_holder,
             };
+            {
+                let mut sequence_paxos = self.sequence_paxos.lock().unwrap();
+                // the command is forwarded to the current leader, or held until there is one
+                let ballot = match sequence_paxos.get_current_leader() {
+                    0 => None,
+                    _ => {
+                        let leader = *self.log_stats.leader.lock().unwrap();
+                        let promise = *self.log_stats.promise.lock().unwrap();
+                        Some(if leader < promise { promise } else { leader })
+                    }
+                };
+                self.query_results_holder.lock().unwrap().insert_notifier(id, notify.clone(), ballot);
+                // a stopped configuration takes no more entries
+                if sequence_paxos.append(cmd).is_err() {
+                    return Err(StoreError::ProposalDropped);
+                }
+            }
 
             // wait for append (and decide) to finish in background
-            notify.notified().await;
+            until_deadline(deadline, notify.notified()).await?;
             let results = self.query_results_holder.lock().unwrap().remove_result(&id).unwrap();
             results?
         };
@@ -460,9 +1608,22 @@ impl<T: StoreTransport + Send + Sync> StoreServer<T> {
     }
 
     /// Receive a sequence paxos message from the ChiselStore cluster.
-    pub fn recv_sp_msg(&self, msg: Message<StoreCommand, ()>) {
+    pub fn recv_sp_msg(&self, msg: Message<StoreCommand, SQLiteSnapshot>) {
+        if let PaxosMsg::Prepare(prepare) = &msg.msg {
+            if self.granted_lease_to_other(prepare.n) {
+                self.deferred_msgs.lock().unwrap().push(msg);
+                return;
+            }
+        }
+        // only the leader decides entries
+        let decides = matches!(msg.msg, PaxosMsg::AcceptDecide(_) | PaxosMsg::Decide(_));
         let mut sequence_paxos = self.sequence_paxos.lock().unwrap();
         sequence_paxos.handle(msg);
+        let accepted_round = *self.log_stats.accepted_round.lock().unwrap();
+        self.query_results_holder.lock().unwrap().drop_unsynced(accepted_round);
+        if decides {
+            *self.synced_at.lock().unwrap() = Some(Instant::now());
+        }
     }
     
     /// Receive a ballot leader election message from the ChiselStore cluster.
@@ -471,6 +1632,76 @@ impl<T: StoreTransport + Send + Sync> StoreServer<T> {
         ballot_leader_election.handle(msg);
     }
 
+    fn incoming_snapshot_path(&self, idx: u64, checksum: u32) -> PathBuf {
+        self.config.node_file_path(self.this_id, &format!("-incoming-snapshot{}-{:08x}.db", idx, checksum))
+    }
+
+    /// Number of bytes of a snapshot image received so far from another node.
+    pub fn snapshot_transfer_offset(&self, idx: u64, size: u64, checksum: u32) -> Result<u64, StoreError> {
+        let path = self.incoming_snapshot_path(idx, checksum);
+        if path.exists() {
+            return Ok(size);
+        }
+        match std::fs::metadata(path.with_extension("part")) {
+            Ok(metadata) => Ok(metadata.len()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(0),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Store a chunk of a snapshot image streamed by another node.
+    ///
+    /// Returns the number of bytes received so far. Chunks that do not continue
+    /// where the previous one stopped are ignored, and the sender resumes from
+    /// the returned offset.
+    pub fn write_snapshot_chunk(&self, idx: u64, size: u64, checksum: u32, offset: u64, data: &[u8], chunk_checksum: u32) -> Result<u64, StoreError> {
+        if crc32fast::hash(data) != chunk_checksum {
+            return Err(StoreError::CorruptSnapshot(format!("chunk at offset {} does not match its checksum", offset)));
+        }
+        // concurrent transfers of the same image, e.g. a resumed one racing
+        // the one it replaces, take turns appending to it
+        let transfer = self.snapshot_transfers.lock().unwrap().entry((idx, checksum)).or_default().clone();
+        let _transfer = transfer.lock().unwrap();
+        let received = self.write_snapshot_bytes(idx, size, checksum, offset, data);
+        if matches!(received, Ok(received) if received >= size) {
+            self.snapshot_transfers.lock().unwrap().remove(&(idx, checksum));
+        }
+        received
+    }
+
+    fn write_snapshot_bytes(&self, idx: u64, size: u64, checksum: u32, offset: u64, data: &[u8]) -> Result<u64, StoreError> {
+        let received = self.snapshot_transfer_offset(idx, size, checksum)?;
+        if offset != received {
+            return Ok(received);
+        }
+
+        let path = self.incoming_snapshot_path(idx, checksum);
+        let part = path.with_extension("part");
+        let mut file = std::fs::OpenOptions::new().create(true).append(true).open(&part)?;
+        file.write_all(data)?;
+        let received = received + data.len() as u64;
+        if received >= size {
+            file.sync_all()?;
+            let image = SnapshotImage { idx, size, checksum, path: part.clone() };
+            if let Err(e) = verify_image(&image) {
+                // start the transfer over
+                std::fs::remove_file(&part)?;
+                return Err(e);
+            }
+            std::fs::rename(&part, &path)?;
+        }
+        Ok(received)
+    }
+
+    /// Snapshot image that was completely streamed from another node.
+    pub fn staged_snapshot_image(&self, idx: u64, size: u64, checksum: u32) -> Result<SnapshotImage, StoreError> {
+        let path = self.incoming_snapshot_path(idx, checksum);
+        if std::fs::metadata(&path)?.len() != size {
+            return Err(StoreError::CorruptSnapshot(format!("image {} is incomplete", path.display())));
+        }
+        Ok(SnapshotImage { idx, size, checksum, path })
+    }
+
     /// Used to shutdown replica
     pub fn set_halt(&self, new_halt: bool) {
         let mut halt = self.halt.lock().unwrap();
@@ -495,7 +1726,39 @@ impl<T: StoreTransport + Send + Sync> StoreServer<T> {
     }
 }
 
-fn new_sequence_paxos(configuration_id: u32, pid: u64, peers: Vec<u64>, query_results_holder: Arc<Mutex<QueryResultsHolder>>, skip_prepare_use_leader: Option<Ballot>) -> SequencePaxos<StoreCommand, (), SQLiteStore<()>> {
+/// How long a streamed query may read for.
+///
+/// The stream reads in a transaction, which keeps the write-ahead log from
+/// being checkpointed for as long as it lasts. Without a write-ahead log the
+/// transaction keeps decided entries from being applied, which holds up
+/// consensus, so the stream ends well before a leader is deemed lost.
+fn max_stream_duration(storage_mode: StorageMode) -> Duration {
+    match storage_mode {
+        StorageMode::Disk => Duration::from_millis(STREAM_MAX_DURATION_MS),
+        StorageMode::Memory => Duration::from_millis(BLE_LOOP_TIMEOUT_MS),
+    }
+}
+
+/// Splits `results` into batches of at most [`STREAM_BATCH_ROWS`] rows, the
+/// first one carrying the columns and the last one the rows changed.
+fn into_batches(mut results: QueryResults) -> Vec<QueryResults> {
+    let mut batches = vec![];
+    while results.rows.len() > STREAM_BATCH_ROWS {
+        let rest = results.rows.split_off(STREAM_BATCH_ROWS);
+        batches.push(QueryResults {
+            columns: std::mem::take(&mut results.columns),
+            rows: std::mem::replace(&mut results.rows, rest),
+            rows_affected: 0,
+            last_insert_rowid: None,
+            applied_idx: results.applied_idx,
+            config_id: results.config_id,
+        });
+    }
+    batches.push(results);
+    batches
+}
+
+fn new_sequence_paxos(configuration_id: u32, pid: u64, peers: Vec<u64>, config: &StoreConfig, conn_pool: Arc<ConnectionPool>, query_results_holder: Arc<Mutex<QueryResultsHolder>>, log_stats: Arc<LogStats>, skip_prepare_use_leader: Option<Ballot>) -> Result<SequencePaxos<StoreCommand, SQLiteSnapshot, SQLiteStore>, StoreError> {
     let mut sp_config = SequencePaxosConfig::default();
     sp_config.set_configuration_id(configuration_id);
     sp_config.set_pid(pid);
@@ -504,8 +1767,13 @@ fn new_sequence_paxos(configuration_id: u32, pid: u64, peers: Vec<u64>, query_re
         sp_config.set_skip_prepare_use_leader(b);
     }
 
-    let store_config = StoreConfig { conn_pool_size: 20, query_results_holder };
-    let sqlite_store = SQLiteStore::new(pid, store_config);
+    let sqlite_store = SQLiteStore::new(pid, configuration_id, config, conn_pool, query_results_holder, log_stats)?;
+    let recovered = sqlite_store.recovered;
     
-    SequencePaxos::with(sp_config, sqlite_store)
+    let mut sequence_paxos = SequencePaxos::with(sp_config, sqlite_store);
+    if recovered {
+        // we crashed while part of this configuration, ask the leader to resync us.
+        sequence_paxos.fail_recovery();
+    }
+    Ok(sequence_paxos)
 }
@@ -0,0 +1,308 @@
+//! ChiselStore snapshots.
+//!
+//! A snapshot is an optional image of the SQLite database, taken at some
+//! decided index, followed by the store commands decided after it. Images
+//! are taken with `VACUUM INTO` and installed by copying the schema and the
+//! contents of the image into the live database in a single transaction.
+//! In-memory databases are copied to and from images with the backup API
+//! instead.
+
+use crate::errors::StoreError;
+use crate::memdb;
+use crate::StoreCommand;
+use omnipaxos_core::storage::Snapshot;
+use sqlite::{Connection, OpenFlags, State};
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Image of the SQLite database at a decided index.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SnapshotImage {
+    /// Decided index the image reflects.
+    pub idx: u64,
+    /// Size of the image in bytes.
+    pub size: u64,
+    /// CRC32 checksum of the image.
+    pub checksum: u32,
+    /// Location of the image on the local node.
+    pub path: PathBuf,
+}
+
+/// Snapshot of the replicated SQLite database.
+#[derive(Clone, Debug, Default)]
+pub struct SQLiteSnapshot {
+    /// Database image the snapshot is based on, if any.
+    pub image: Option<SnapshotImage>,
+    /// Store commands decided after the image.
+    pub entries: Vec<StoreCommand>,
+}
+
+impl SQLiteSnapshot {
+    /// Decided index the snapshot reflects.
+    pub fn idx(&self) -> u64 {
+        let base = self.image.as_ref().map(|image| image.idx).unwrap_or(0);
+        base + self.entries.len() as u64
+    }
+}
+
+impl Snapshot<StoreCommand> for SQLiteSnapshot {
+    fn create(entries: &[StoreCommand]) -> Self {
+        SQLiteSnapshot {
+            image: None,
+            entries: entries.to_vec(),
+        }
+    }
+
+    fn merge(&mut self, delta: Self) {
+        match delta.image {
+            // a delta with an image is complete on its own
+            Some(_) => *self = delta,
+            None => self.entries.extend(delta.entries),
+        }
+    }
+
+    fn use_snapshots() -> bool {
+        true
+    }
+}
+
+/// Image files read by snapshot transfers to other nodes.
+static PINNED_IMAGES: Mutex<Vec<PinnedImage>> = Mutex::new(Vec::new());
+
+#[derive(Debug)]
+struct PinnedImage {
+    path: PathBuf,
+    /// Number of transfers reading the image.
+    pins: usize,
+    /// Whether the image is removed once the transfers are done.
+    removed: bool,
+}
+
+/// Keeps an image file from being removed while it is streamed to another node.
+#[derive(Debug)]
+pub(crate) struct ImagePin {
+    path: PathBuf,
+}
+
+/// Pins the image file at `path` until the returned pin is dropped.
+pub(crate) fn pin_image(path: &Path) -> ImagePin {
+    let mut pinned = PINNED_IMAGES.lock().unwrap();
+    match pinned.iter_mut().find(|image| image.path == path) {
+        Some(image) => image.pins += 1,
+        None => pinned.push(PinnedImage {
+            path: path.to_path_buf(),
+            pins: 1,
+            removed: false,
+        }),
+    }
+    ImagePin {
+        path: path.to_path_buf(),
+    }
+}
+
+impl Drop for ImagePin {
+    fn drop(&mut self) {
+        let mut pinned = PINNED_IMAGES.lock().unwrap();
+        if let Some(pos) = pinned.iter().position(|image| image.path == self.path) {
+            pinned[pos].pins -= 1;
+            if pinned[pos].pins == 0 {
+                let image = pinned.remove(pos);
+                if image.removed {
+                    let _ = std::fs::remove_file(&image.path);
+                }
+            }
+        }
+    }
+}
+
+/// Removes the image file at `path`, or once the transfers reading it are done.
+pub(crate) fn remove_image(path: &Path) {
+    let mut pinned = PINNED_IMAGES.lock().unwrap();
+    match pinned.iter_mut().find(|image| image.path == path) {
+        Some(image) => image.removed = true,
+        None => {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Takes an image of the database `conn` is connected to and stores it in `path`.
+pub(crate) fn capture_image(conn: &Connection, idx: u64, path: &Path) -> Result<SnapshotImage, StoreError> {
+    if path.exists() {
+        std::fs::remove_file(path)?;
+    }
+    conn.execute(format!("VACUUM INTO {}", quote_literal(&path.to_string_lossy())))?;
+    let (size, checksum) = checksum_file(path)?;
+    Ok(SnapshotImage {
+        idx,
+        size,
+        checksum,
+        path: path.to_path_buf(),
+    })
+}
+
+/// Atomically replaces the contents of the database `conn` is connected to with `image`.
+///
+/// `finish` runs in the same transaction, after the image has been copied.
+pub(crate) fn restore_image<F>(conn: &Connection, image: &SnapshotImage, finish: F) -> Result<(), StoreError>
+where
+    F: FnOnce(&Connection) -> Result<(), StoreError>,
+{
+    verify_image(image)?;
+    conn.execute(format!(
+        "ATTACH DATABASE {} AS snapshot",
+        quote_literal(&image.path.to_string_lossy())
+    ))?;
+    let result = copy_attached_image(conn, finish);
+    conn.execute("DETACH DATABASE snapshot")?;
+    result
+}
+
+/// Takes an image of the in-memory database `conn` is connected to and stores it in `path`.
+pub(crate) fn capture_memdb_image(conn: &mut Connection, idx: u64, path: &Path) -> Result<SnapshotImage, StoreError> {
+    if path.exists() {
+        std::fs::remove_file(path)?;
+    }
+    {
+        let flags = OpenFlags::new().set_read_write().set_create();
+        let mut image_conn = Connection::open_with_flags(path, flags)?;
+        memdb::copy(conn, &mut image_conn)?;
+    }
+    let (size, checksum) = checksum_file(path)?;
+    Ok(SnapshotImage {
+        idx,
+        size,
+        checksum,
+        path: path.to_path_buf(),
+    })
+}
+
+/// Atomically replaces the contents of the in-memory database `conn` is connected to with `image`.
+///
+/// `finish` runs once the image has been copied. In-memory databases do not
+/// survive a crash, so it does not need to run in the same transaction.
+pub(crate) fn restore_memdb_image<F>(conn: &mut Connection, image: &SnapshotImage, finish: F) -> Result<(), StoreError>
+where
+    F: FnOnce(&Connection) -> Result<(), StoreError>,
+{
+    verify_image(image)?;
+    {
+        let mut image_conn = Connection::open_with_flags(&image.path, OpenFlags::new().set_read_only())?;
+        memdb::copy(&mut image_conn, conn)?;
+    }
+    finish(conn)
+}
+
+/// Checks that the image on disk matches its size and checksum.
+pub(crate) fn verify_image(image: &SnapshotImage) -> Result<(), StoreError> {
+    let (size, checksum) = checksum_file(&image.path)?;
+    if size != image.size || checksum != image.checksum {
+        return Err(StoreError::CorruptSnapshot(format!(
+            "image {} does not match its checksum",
+            image.path.display()
+        )));
+    }
+    Ok(())
+}
+
+/// Returns the size and CRC32 checksum of the file at `path`.
+pub(crate) fn checksum_file(path: &Path) -> Result<(u64, u32), StoreError> {
+    let mut file = File::open(path)?;
+    let mut hasher = crc32fast::Hasher::new();
+    let mut buf = vec![0; 64 * 1024];
+    let mut size = 0;
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        size += n as u64;
+    }
+    Ok((size, hasher.finalize()))
+}
+
+fn copy_attached_image<F>(conn: &Connection, finish: F) -> Result<(), StoreError>
+where
+    F: FnOnce(&Connection) -> Result<(), StoreError>,
+{
+    conn.execute("BEGIN IMMEDIATE")?;
+    match replace_schema(conn).and_then(|_| finish(conn)) {
+        Ok(()) => {
+            conn.execute("COMMIT")?;
+            Ok(())
+        }
+        Err(e) => {
+            let _ = conn.execute("ROLLBACK");
+            Err(e)
+        }
+    }
+}
+
+fn replace_schema(conn: &Connection) -> Result<(), StoreError> {
+    // drop everything in the live database, indices and triggers go with their tables
+    for (kind, name, _) in schema_objects(conn, "main")? {
+        match kind.as_str() {
+            "table" => conn.execute(format!("DROP TABLE IF EXISTS main.{}", quote_ident(&name)))?,
+            "view" => conn.execute(format!("DROP VIEW IF EXISTS main.{}", quote_ident(&name)))?,
+            _ => {}
+        }
+    }
+
+    let objects = schema_objects(conn, "snapshot")?;
+    for (kind, name, sql) in objects.iter() {
+        if kind == "table" {
+            conn.execute(sql)?;
+            conn.execute(format!(
+                "INSERT INTO main.{0} SELECT * FROM snapshot.{0}",
+                quote_ident(name)
+            ))?;
+        }
+    }
+    if has_table(conn, "main", "sqlite_sequence")? {
+        conn.execute("DELETE FROM main.sqlite_sequence")?;
+        if has_table(conn, "snapshot", "sqlite_sequence")? {
+            conn.execute("INSERT INTO main.sqlite_sequence SELECT * FROM snapshot.sqlite_sequence")?;
+        }
+    }
+    for (kind, _, sql) in objects.iter() {
+        if kind != "table" {
+            conn.execute(sql)?;
+        }
+    }
+    Ok(())
+}
+
+/// Lists the user-defined objects of `schema` in creation order.
+fn schema_objects(conn: &Connection, schema: &str) -> Result<Vec<(String, String, String)>, StoreError> {
+    let mut stmt = conn.prepare(format!(
+        "SELECT type, name, sql FROM {}.sqlite_master \
+         WHERE sql IS NOT NULL AND name NOT LIKE 'sqlite_%' ORDER BY rowid",
+        schema
+    ))?;
+    let mut objects = vec![];
+    while let State::Row = stmt.next()? {
+        objects.push((stmt.read::<String>(0)?, stmt.read::<String>(1)?, stmt.read::<String>(2)?));
+    }
+    Ok(objects)
+}
+
+fn has_table(conn: &Connection, schema: &str, name: &str) -> Result<bool, StoreError> {
+    let mut stmt = conn.prepare(format!(
+        "SELECT COUNT(*) FROM {}.sqlite_master WHERE type = 'table' AND name = ?",
+        schema
+    ))?;
+    stmt.bind(1, name)?;
+    stmt.next()?;
+    Ok(stmt.read::<i64>(0)? > 0)
+}
+
+fn quote_ident(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+fn quote_literal(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "''"))
+}
@@ -0,0 +1,97 @@
+//! ChiselStore values.
+
+/// SQLite value.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    /// NULL.
+    Null,
+    /// Signed integer.
+    Integer(i64),
+    /// Floating point number.
+    Real(f64),
+    /// UTF-8 text.
+    Text(String),
+    /// Binary data.
+    Blob(Vec<u8>),
+}
+
+impl Value {
+    /// Size of the value in bytes.
+    pub(crate) fn size(&self) -> u64 {
+        match self {
+            Value::Null => 0,
+            Value::Integer(_) | Value::Real(_) => 8,
+            Value::Text(s) => s.len() as u64,
+            Value::Blob(b) => b.len() as u64,
+        }
+    }
+}
+
+impl From<i64> for Value {
+    fn from(v: i64) -> Self {
+        Value::Integer(v)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(v: f64) -> Self {
+        Value::Real(v)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(v: &str) -> Self {
+        Value::Text(v.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(v: String) -> Self {
+        Value::Text(v)
+    }
+}
+
+impl From<Vec<u8>> for Value {
+    fn from(v: Vec<u8>) -> Self {
+        Value::Blob(v)
+    }
+}
+
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(v: Option<T>) -> Self {
+        v.map_or(Value::Null, Into::into)
+    }
+}
+
+/// Parameters bound to a SQL statement.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Params {
+    /// No parameters.
+    None,
+    /// Parameters bound by position, the first one to `?1`. Every statement
+    /// that takes parameters has to take exactly as many as are given.
+    Positional(Vec<Value>),
+    /// Parameters bound by name, such as `:id`, `@id` or `$id`. A name
+    /// without a prefix is bound as `:name`.
+    Named(Vec<(String, Value)>),
+}
+
+impl Default for Params {
+    fn default() -> Self {
+        Params::None
+    }
+}
+
+impl Params {
+    /// Size of the parameters in bytes.
+    pub(crate) fn size(&self) -> u64 {
+        match self {
+            Params::None => 0,
+            Params::Positional(values) => values.iter().map(Value::size).sum(),
+            Params::Named(values) => values
+                .iter()
+                .map(|(name, value)| name.len() as u64 + value.size())
+                .sum(),
+        }
+    }
+}
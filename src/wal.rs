@@ -0,0 +1,283 @@
+//! ChiselStore write-ahead log.
+//!
+//! The write-ahead log persists the replicated log and the Sequence Paxos
+//! state of a node, so that a restarted node remembers its promises and
+//! accepted entries. Every record is framed as
+//! `[len: u32][crc32(len): u32][crc32(payload): u32][payload]` and appended to
+//! a single file next to the SQLite database.
+
+use crate::errors::StoreError;
+use crate::rpc::proto;
+use crate::rpc::{
+    ballot_from_proto, proto_from_ballot, proto_from_snapshot, proto_from_stopsign,
+    proto_from_store_command, snapshot_from_proto, stopsign_from_proto, store_command_from_proto,
+};
+use crate::snapshot::SQLiteSnapshot;
+use crate::StoreCommand;
+use omnipaxos_core::{ballot_leader_election::Ballot, storage::StopSignEntry};
+use prost::Message;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+/// Size of the record header: payload length, length checksum and payload checksum.
+const HEADER_LEN: usize = 12;
+
+/// A single durable change to the Sequence Paxos state.
+#[derive(Debug)]
+pub(crate) enum WalRecord {
+    /// Entries appended to the end of the log.
+    Append(Vec<StoreCommand>),
+    /// Log truncated to the given length.
+    Truncate(u64),
+    /// First entries of the log removed up to the given index.
+    Trim(u64),
+    /// Promised round.
+    Promise(Ballot),
+    /// Accepted round.
+    AcceptedRound(Ballot),
+    /// Length of the decided log.
+    DecidedIdx(u64),
+    /// Garbage collected index.
+    CompactedIdx(u64),
+    /// StopSign.
+    StopSign(StopSignEntry),
+    /// Stored snapshot.
+    Snapshot(SQLiteSnapshot),
+}
+
+/// State recovered by replaying the write-ahead log.
+#[derive(Debug, Default)]
+pub(crate) struct WalState {
+    pub(crate) log: Vec<StoreCommand>,
+    pub(crate) n_prom: Ballot,
+    pub(crate) acc_round: Ballot,
+    pub(crate) ld: u64,
+    pub(crate) trimmed_idx: u64,
+    pub(crate) stopsign: Option<StopSignEntry>,
+    pub(crate) snapshot: Option<SQLiteSnapshot>,
+}
+
+impl WalState {
+    fn apply(&mut self, record: WalRecord) {
+        match record {
+            WalRecord::Append(mut entries) => self.log.append(&mut entries),
+            WalRecord::Truncate(len) => self.log.truncate(len as usize),
+            WalRecord::Trim(idx) => {
+                let idx = std::cmp::min(idx as usize, self.log.len());
+                self.log.drain(0..idx);
+            }
+            WalRecord::Promise(n_prom) => self.n_prom = n_prom,
+            WalRecord::AcceptedRound(acc_round) => self.acc_round = acc_round,
+            WalRecord::DecidedIdx(ld) => self.ld = ld,
+            WalRecord::CompactedIdx(trimmed_idx) => self.trimmed_idx = trimmed_idx,
+            WalRecord::StopSign(ss) => self.stopsign = Some(ss),
+            WalRecord::Snapshot(snapshot) => self.snapshot = Some(snapshot),
+        }
+    }
+
+    /// Records that rebuild this state from scratch.
+    fn records(&self) -> Vec<WalRecord> {
+        let mut records = vec![
+            WalRecord::Append(self.log.clone()),
+            WalRecord::Promise(self.n_prom),
+            WalRecord::AcceptedRound(self.acc_round),
+            WalRecord::DecidedIdx(self.ld),
+            WalRecord::CompactedIdx(self.trimmed_idx),
+        ];
+        if let Some(ss) = &self.stopsign {
+            records.push(WalRecord::StopSign(ss.clone()));
+        }
+        if let Some(snapshot) = &self.snapshot {
+            records.push(WalRecord::Snapshot(snapshot.clone()));
+        }
+        records
+    }
+}
+
+/// Append-only, checksummed log file.
+#[derive(Debug)]
+pub(crate) struct Wal {
+    path: PathBuf,
+    file: File,
+}
+
+impl Wal {
+    /// Opens the write-ahead log at `path` and replays it.
+    ///
+    /// A torn record at the end of the file, e.g. from a crash in the middle
+    /// of a write, is truncated away. Any other damaged record is a
+    /// [`StoreError::CorruptLog`], as the records after it cannot be replayed.
+    pub(crate) fn open<P: AsRef<Path>>(path: P) -> Result<(Self, WalState), StoreError> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(&path)?;
+        let mut buf = vec![];
+        file.read_to_end(&mut buf)?;
+
+        let mut state = WalState::default();
+        let mut offset = 0;
+        while offset < buf.len() {
+            let rest = &buf[offset..];
+            match read_frame(rest) {
+                Frame::Complete(payload, len) => {
+                    let record = decode_record(payload).ok_or_else(|| {
+                        StoreError::CorruptLog(format!("record at offset {} in {} cannot be decoded", offset, path.display()))
+                    })?;
+                    state.apply(record);
+                    offset += len;
+                }
+                // torn write at the end of the log
+                Frame::Incomplete => break,
+                Frame::Corrupt(len) if len == rest.len() => break,
+                Frame::Corrupt(_) | Frame::CorruptHeader => {
+                    return Err(StoreError::CorruptLog(format!(
+                        "record at offset {} in {} does not match its checksum",
+                        offset,
+                        path.display()
+                    )))
+                }
+            }
+        }
+        if offset < buf.len() {
+            file.set_len(offset as u64)?;
+            file.sync_all()?;
+        }
+        // Appends always go to the end of the (possibly truncated) file.
+        drop(file);
+        let file = OpenOptions::new().append(true).open(&path)?;
+
+        Ok((Wal { path, file }, state))
+    }
+
+    /// Durably appends `record` to the log.
+    pub(crate) fn append(&mut self, record: WalRecord) -> Result<(), StoreError> {
+        self.file.write_all(&encode_record(record))?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    /// Atomically replaces the log with a compact one describing `state`.
+    pub(crate) fn checkpoint(&mut self, state: &WalState) -> Result<(), StoreError> {
+        let tmp_path = self.path.with_extension("wal.tmp");
+        let mut tmp = File::create(&tmp_path)?;
+        for record in state.records() {
+            tmp.write_all(&encode_record(record))?;
+        }
+        tmp.sync_all()?;
+        std::fs::rename(&tmp_path, &self.path)?;
+        sync_parent_dir(&self.path)?;
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        Ok(())
+    }
+}
+
+fn encode_record(record: WalRecord) -> Vec<u8> {
+    use proto::wal_record::Record;
+
+    let record = match record {
+        WalRecord::Append(entries) => Record::Append(proto::wal_record::Entries {
+            store_commands: entries.into_iter().map(proto_from_store_command).collect(),
+        }),
+        WalRecord::Truncate(len) => Record::Truncate(len),
+        WalRecord::Trim(idx) => Record::Trim(idx),
+        WalRecord::Promise(n_prom) => Record::Promise(proto_from_ballot(n_prom)),
+        WalRecord::AcceptedRound(acc_round) => Record::AcceptedRound(proto_from_ballot(acc_round)),
+        WalRecord::DecidedIdx(ld) => Record::DecidedIdx(ld),
+        WalRecord::CompactedIdx(idx) => Record::CompactedIdx(idx),
+        WalRecord::StopSign(ss) => Record::StopSign(proto::StopSignEntry {
+            ss: Some(proto_from_stopsign(ss.stopsign)),
+            decided: ss.decided,
+        }),
+        WalRecord::Snapshot(snapshot) => Record::Snapshot(proto_from_snapshot(snapshot)),
+    };
+    let payload = proto::WalRecord {
+        record: Some(record),
+    }
+    .encode_to_vec();
+    encode_frame(&payload)
+}
+
+/// Makes the creation, removal or renaming of the file at `path` durable.
+pub(crate) fn sync_parent_dir(path: &Path) -> Result<(), StoreError> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+/// Decodes the record in the `payload` of a frame, or returns `None` if it
+/// is not a valid record.
+fn decode_record(payload: &[u8]) -> Option<WalRecord> {
+    use proto::wal_record::Record;
+
+    let record = match proto::WalRecord::decode(payload).ok()?.record? {
+        Record::Append(entries) => WalRecord::Append(
+            entries
+                .store_commands
+                .into_iter()
+                .map(store_command_from_proto)
+                .collect(),
+        ),
+        Record::Truncate(len) => WalRecord::Truncate(len),
+        Record::Trim(idx) => WalRecord::Trim(idx),
+        Record::Promise(n_prom) => WalRecord::Promise(ballot_from_proto(n_prom)),
+        Record::AcceptedRound(acc_round) => WalRecord::AcceptedRound(ballot_from_proto(acc_round)),
+        Record::DecidedIdx(ld) => WalRecord::DecidedIdx(ld),
+        Record::CompactedIdx(idx) => WalRecord::CompactedIdx(idx),
+        Record::StopSign(ss) => WalRecord::StopSign(StopSignEntry {
+            stopsign: stopsign_from_proto(ss.ss?),
+            decided: ss.decided,
+        }),
+        Record::Snapshot(snapshot) => WalRecord::Snapshot(snapshot_from_proto(snapshot)),
+    };
+    Some(record)
+}
+
+/// A frame read from the start of a buffer.
+pub(crate) enum Frame<'a> {
+    /// Payload of an intact frame and the framed length.
+    Complete(&'a [u8], usize),
+    /// The buffer ends before the frame does.
+    Incomplete,
+    /// The payload does not match its checksum, with the framed length.
+    Corrupt(usize),
+    /// The length does not match its checksum, so the frame cannot be delimited.
+    CorruptHeader,
+}
+
+/// Frames `payload` as `[len: u32][crc32(len): u32][crc32(payload): u32][payload]`.
+pub(crate) fn encode_frame(payload: &[u8]) -> Vec<u8> {
+    let len = (payload.len() as u32).to_le_bytes();
+    let mut buf = Vec::with_capacity(HEADER_LEN + payload.len());
+    buf.extend_from_slice(&len);
+    buf.extend_from_slice(&crc32fast::hash(&len).to_le_bytes());
+    buf.extend_from_slice(&crc32fast::hash(payload).to_le_bytes());
+    buf.extend_from_slice(payload);
+    buf
+}
+
+/// Reads the frame at the start of `buf`.
+pub(crate) fn read_frame(buf: &[u8]) -> Frame<'_> {
+    if buf.len() < HEADER_LEN {
+        return Frame::Incomplete;
+    }
+    if crc32fast::hash(&buf[0..4]) != u32::from_le_bytes(buf[4..8].try_into().unwrap()) {
+        return Frame::CorruptHeader;
+    }
+    let len = u32::from_le_bytes(buf[0..4].try_into().unwrap()) as usize;
+    let crc = u32::from_le_bytes(buf[8..12].try_into().unwrap());
+    let payload = match buf.get(HEADER_LEN..HEADER_LEN + len) {
+        Some(payload) => payload,
+        None => return Frame::Incomplete,
+    };
+    if crc32fast::hash(payload) != crc {
+        return Frame::Corrupt(HEADER_LEN + len);
+    }
+    Frame::Complete(payload, HEADER_LEN + len)
+}
@@ -1,7 +1,9 @@
 use chiselstore::rpc::proto::rpc_server::RpcServer;
 use chiselstore::{
+    config::{CompactionMode, CompactionPolicy, LeaderLease, StorageMode},
+    log_storage::{FileLogStorage, LogStorage, MemoryLogStorage},
     rpc::{RpcService, RpcTransport},
-    StoreServer,
+    StoreConfig, StoreError, StoreServer,
 };
 use std::sync::Arc;
 use tonic::transport::Server;
@@ -13,7 +15,7 @@ pub mod proto {
   tonic::include_proto!("proto");
 }
 use proto::rpc_client::RpcClient;
-use proto::{Query};
+use proto::{BatchReq, Consistency, NamedValue, Query, RequestId, Statement, Value};
 use tokio::sync::oneshot;
 
 use slog::info;
@@ -79,10 +81,15 @@ impl Replica {
 }
 
 async fn start_replica(id: u64, peers: Vec<u64>) -> Replica {
+    start_replica_with_config(id, peers, StoreConfig::default()).await
+}
+
+async fn start_replica_with_config(id: u64, peers: Vec<u64>, mut config: StoreConfig) -> Replica {
     let (host, port) = node_authority(id);
     let rpc_listen_addr = format!("{}:{}", host, port).parse().unwrap();
     let transport = RpcTransport::new(Box::new(node_rpc_addr));
-    let server = StoreServer::start(id, peers, transport).unwrap();
+    config.set_data_dir(node_data_dir(id));
+    let server = StoreServer::start_with_config(id, peers, transport, config).unwrap();
     let server = Arc::new(server);
     let (halt_sender, halt_receiver) = oneshot::channel::<()>();
     let store_handles = {
@@ -132,8 +139,25 @@ async fn start_replica(id: u64, peers: Vec<u64>) -> Replica {
     }
 }
 
+/// Node data directory.
+fn node_data_dir(id: u64) -> std::path::PathBuf {
+    std::env::temp_dir().join("chiselstore-tests").join(format!("node{}", id))
+}
+
+/// Remove the durable state left behind by a previous cluster.
+fn remove_replica_state(id: u64) {
+    let data_dir = node_data_dir(id);
+    if data_dir.exists() {
+        std::fs::remove_dir_all(&data_dir).unwrap();
+    }
+    std::fs::create_dir_all(&data_dir).unwrap();
+}
+
 async fn setup_replicas(num_replicas: u64) -> Vec<Replica> {
     let mut replicas: Vec<Replica> = Vec::new();
+    for id in 1..(num_replicas+1) {
+        remove_replica_state(id);
+    }
     for id in 1..(num_replicas+1) {
         let mut peers: Vec<u64> = (1..num_replicas+1).collect();
         peers.remove((id - 1) as usize);
@@ -161,6 +185,7 @@ async fn query(replica_id: u64, sql: String) -> Result<String, Box<dyn Error>> {
     // create request
     let query = tonic::Request::new(Query {
         sql: sql,
+        ..Default::default()
     });
 
     // execute request
@@ -170,11 +195,49 @@ async fn query(replica_id: u64, sql: String) -> Result<String, Box<dyn Error>> {
         return Ok(String::from(""));
     }
 
-    let res = response.rows[0].values[0].clone();
+    let res = value_to_string(&response.rows[0].values[0]);
 
     Ok(res)
 }
 
+/// Formats a column value the way SQLite casts it to text.
+fn value_to_string(value: &Value) -> String {
+    use proto::value::Value as V;
+    match &value.value {
+        None | Some(V::Null(_)) => String::from(""),
+        Some(V::Integer(i)) => i.to_string(),
+        Some(V::Real(r)) => r.to_string(),
+        Some(V::Text(t)) => t.clone(),
+        Some(V::Blob(b)) => String::from_utf8_lossy(b).to_string(),
+    }
+}
+
+async fn query_with_params(replica_id: u64, sql: &str, params: Vec<Value>, named_params: Vec<NamedValue>) -> Result<String, Box<dyn Error>> {
+    let addr = node_rpc_addr(replica_id);
+    let mut client = RpcClient::connect(addr).await.unwrap();
+
+    let query = tonic::Request::new(Query {
+        sql: sql.to_string(),
+        params,
+        named_params,
+        ..Default::default()
+    });
+
+    let response = client.execute(query).await?.into_inner();
+    if response.rows.len() == 0 || response.rows[0].values.len() == 0 {
+        return Ok(String::from(""));
+    }
+    Ok(value_to_string(&response.rows[0].values[0]))
+}
+
+fn text(s: &str) -> Value {
+    Value { value: Some(proto::value::Value::Text(s.to_string())) }
+}
+
+fn integer(i: i64) -> Value {
+    Value { value: Some(proto::value::Value::Integer(i)) }
+}
+
 #[tokio::test(flavor = "multi_thread")]
 async fn connect_to_cluster() {
     let mut replicas = setup_replicas(2).await;
@@ -220,6 +283,1157 @@ async fn write_read() {
     shutdown_replicas(replicas).await;
 }
 
+#[tokio::test(flavor = "multi_thread")]
+async fn parameterized_statements() {
+    let replicas = setup_replicas(2).await;
+
+    tokio::task::spawn(async {
+        query(1, String::from("CREATE TABLE IF NOT EXISTS test_params (id integer PRIMARY KEY, name text)")).await.unwrap();
+
+        // positional parameters, the value is never interpolated into the SQL
+        query_with_params(1, "INSERT INTO test_params VALUES(?, ?)", vec![integer(1), text("O'Brien")], vec![]).await.unwrap();
+
+        // named parameters, with and without prefix
+        let named = vec![
+            NamedValue { name: String::from(":id"), value: Some(integer(2)) },
+            NamedValue { name: String::from("name"), value: Some(text("Robert'); DROP TABLE test_params;--")) },
+        ];
+        query_with_params(1, "INSERT INTO test_params VALUES(:id, :name)", vec![], named).await.unwrap();
+
+        let res = query_with_params(2, "SELECT name FROM test_params WHERE id = ?", vec![integer(1)], vec![]).await.unwrap();
+        assert!(res == "O'Brien");
+        let res = query_with_params(2, "SELECT name FROM test_params WHERE id = ?", vec![integer(2)], vec![]).await.unwrap();
+        assert!(res == "Robert'); DROP TABLE test_params;--");
+
+        // unknown names are rejected
+        let unknown = vec![NamedValue { name: String::from(":missing"), value: Some(integer(3)) }];
+        assert!(query_with_params(1, "SELECT :id", vec![], unknown).await.is_err());
+
+        // so are too many or too few positional values
+        assert!(query_with_params(1, "SELECT ?", vec![integer(1), integer(2)], vec![]).await.is_err());
+        assert!(query_with_params(1, "SELECT ?, ?", vec![integer(1)], vec![]).await.is_err());
+        assert!(query_with_params(1, "SELECT ?", vec![], vec![]).await.is_err());
+        assert!(query_with_params(1, "SELECT 1", vec![integer(1)], vec![]).await.is_err());
+
+        query(1, String::from("DROP TABLE test_params")).await.unwrap();
+    }).await.unwrap();
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn typed_values() {
+    let replicas = setup_replicas(2).await;
+
+    tokio::task::spawn(async {
+        use proto::value::Value as V;
+
+        query(1, String::from("CREATE TABLE IF NOT EXISTS test_types (i integer, r real, t text, b blob, n integer)")).await.unwrap();
+        query(1, String::from("INSERT INTO test_types VALUES(42, 1.5, 'text', x'00ff', NULL)")).await.unwrap();
+
+        let mut client = RpcClient::connect(node_rpc_addr(2)).await.unwrap();
+        let response = client.execute(tonic::Request::new(Query {
+            sql: String::from("SELECT i, r, t, b, n FROM test_types"),
+            ..Default::default()
+        })).await.unwrap().into_inner();
+        let values: Vec<_> = response.rows[0].values.iter().map(|v| v.value.clone()).collect();
+        assert!(values == vec![
+            Some(V::Integer(42)),
+            Some(V::Real(1.5)),
+            Some(V::Text(String::from("text"))),
+            Some(V::Blob(vec![0x00, 0xff])),
+            Some(V::Null(true)),
+        ]);
+
+        query(1, String::from("DROP TABLE test_types")).await.unwrap();
+    }).await.unwrap();
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn result_columns() {
+    let replicas = setup_replicas(2).await;
+
+    tokio::task::spawn(async {
+        query(1, String::from("CREATE TABLE IF NOT EXISTS test_columns (id integer PRIMARY KEY, name varchar(20))")).await.unwrap();
+        query(1, String::from("INSERT INTO test_columns VALUES(1, 'one')")).await.unwrap();
+
+        let mut client = RpcClient::connect(node_rpc_addr(1)).await.unwrap();
+        let response = client.execute(tonic::Request::new(Query {
+            sql: String::from("SELECT id, name AS label, id + 1 AS next FROM test_columns"),
+            ..Default::default()
+        })).await.unwrap().into_inner();
+        let columns: Vec<_> = response.columns.iter()
+            .map(|c| (c.name.as_str(), c.decl_type.as_deref(), c.table.as_deref()))
+            .collect();
+        assert!(columns == vec![
+            ("id", Some("integer"), Some("test_columns")),
+            ("label", Some("varchar(20)"), Some("test_columns")),
+            ("next", None, None),
+        ]);
+
+        // an empty result set is still described
+        let response = client.execute(tonic::Request::new(Query {
+            sql: String::from("SELECT name FROM test_columns WHERE id = 2"),
+            ..Default::default()
+        })).await.unwrap().into_inner();
+        assert!(response.rows.is_empty());
+        assert!(response.columns[0].name == "name");
+
+        query(1, String::from("DROP TABLE test_columns")).await.unwrap();
+    }).await.unwrap();
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn write_results() {
+    let replicas = setup_replicas(2).await;
+
+    tokio::task::spawn(async {
+        let client = RpcClient::connect(node_rpc_addr(2)).await.unwrap();
+        let execute = |sql: &str| {
+            let request = tonic::Request::new(Query { sql: sql.to_string(), ..Default::default() });
+            let mut client = client.clone();
+            async move { client.execute(request).await.unwrap().into_inner() }
+        };
+
+        execute("CREATE TABLE IF NOT EXISTS test_changes (id integer PRIMARY KEY, value integer)").await;
+
+        let res = execute("INSERT INTO test_changes(value) VALUES(1), (1), (2)").await;
+        assert!(res.rows_affected == 3);
+        assert!(res.last_insert_rowid == Some(3));
+
+        let res = execute("UPDATE test_changes SET value = 3 WHERE value = 1").await;
+        assert!(res.rows_affected == 2);
+        assert!(res.last_insert_rowid == None);
+
+        let res = execute("UPDATE test_changes SET value = 3 WHERE value = 4").await;
+        assert!(res.rows_affected == 0);
+
+        // reads do not report the changes of earlier statements
+        let res = execute("SELECT COUNT(*) FROM test_changes").await;
+        assert!(res.rows_affected == 0);
+
+        execute("DROP TABLE test_changes").await;
+    }).await.unwrap();
+
+    shutdown_replicas(replicas).await;
+}
+
+fn statement(sql: &str, params: Vec<Value>) -> Statement {
+    Statement { sql: sql.to_string(), params, ..Default::default() }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn batch() {
+    let replicas = setup_replicas(2).await;
+
+    tokio::task::spawn(async {
+        let mut client = RpcClient::connect(node_rpc_addr(1)).await.unwrap();
+
+        let request = tonic::Request::new(BatchReq {
+            statements: vec![
+                statement("CREATE TABLE IF NOT EXISTS test_batch (id integer PRIMARY KEY)", vec![]),
+                statement("INSERT INTO test_batch VALUES(?1)", vec![integer(1)]),
+                statement("INSERT INTO test_batch VALUES(?1)", vec![integer(2)]),
+                statement("SELECT COUNT(*) FROM test_batch", vec![]),
+            ],
+            ..Default::default()
+        });
+        let res = client.batch(request).await.unwrap().into_inner();
+        assert!(res.results.len() == 4);
+        assert!(res.results[1].rows_affected == 1);
+        assert!(res.results[2].last_insert_rowid == Some(2));
+        assert!(value_to_string(&res.results[3].rows[0].values[0]) == "2");
+
+        // a failing statement rolls back the whole batch
+        let request = tonic::Request::new(BatchReq {
+            statements: vec![
+                statement("INSERT INTO test_batch VALUES(?1)", vec![integer(3)]),
+                statement("INSERT INTO test_batch VALUES(?1)", vec![integer(1)]),
+            ],
+            ..Default::default()
+        });
+        assert!(client.batch(request).await.is_err());
+        let res = query(2, String::from("SELECT COUNT(*) FROM test_batch")).await.unwrap();
+        assert!(res == "2");
+
+        query(1, String::from("DROP TABLE test_batch")).await.unwrap();
+    }).await.unwrap();
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn transaction_control_is_rejected() {
+    let replicas = setup_replicas(2).await;
+
+    tokio::task::spawn(async {
+        query(1, String::from("CREATE TABLE IF NOT EXISTS test_txn (id integer PRIMARY KEY)")).await.unwrap();
+
+        assert!(query_with_params(1, "BEGIN", vec![], vec![]).await.is_err());
+        assert!(query_with_params(1, "INSERT INTO test_txn VALUES(1); COMMIT", vec![], vec![]).await.is_err());
+        assert!(query_with_params(1, "/* end */ end TRANSACTION", vec![], vec![]).await.is_err());
+
+        // the insert before the rejected COMMIT was rolled back on every node
+        let res = query(2, String::from("SELECT COUNT(*) FROM test_txn")).await.unwrap();
+        assert!(res == "0");
+        query(1, String::from("INSERT INTO test_txn VALUES(1)")).await.unwrap();
+        let res = query(2, String::from("SELECT COUNT(*) FROM test_txn")).await.unwrap();
+        assert!(res == "1");
+
+        query(1, String::from("DROP TABLE test_txn")).await.unwrap();
+    }).await.unwrap();
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn relaxed_reads() {
+    let replicas = setup_replicas(2).await;
+
+    tokio::task::spawn(async {
+        query(1, String::from("CREATE TABLE IF NOT EXISTS test_relaxed (id integer PRIMARY KEY)")).await.unwrap();
+        query(1, String::from("INSERT INTO test_relaxed VALUES(1)")).await.unwrap();
+
+        let mut client = RpcClient::connect(node_rpc_addr(1)).await.unwrap();
+        let request = |sql: &str, consistency: Consistency| {
+            tonic::Request::new(Query {
+                sql: sql.to_string(),
+                consistency: consistency as i32,
+                max_staleness_ms: 60_000,
+                ..Default::default()
+            })
+        };
+
+        // the node that served the write has applied it
+        let res = client.execute(request("SELECT id FROM test_relaxed", Consistency::RelaxedReads)).await.unwrap();
+        assert!(value_to_string(&res.into_inner().rows[0].values[0]) == "1");
+        let res = client.execute(request("SELECT id FROM test_relaxed", Consistency::BoundedStaleness)).await.unwrap();
+        assert!(value_to_string(&res.into_inner().rows[0].values[0]) == "1");
+
+        // writes are replicated whatever the consistency
+        let res = client.execute(request("INSERT INTO test_relaxed VALUES(2)", Consistency::RelaxedReads)).await.unwrap();
+        assert!(res.into_inner().rows_affected == 1);
+        let res = query(2, String::from("SELECT COUNT(*) FROM test_relaxed")).await.unwrap();
+        assert!(res == "2");
+
+        query(1, String::from("DROP TABLE test_relaxed")).await.unwrap();
+    }).await.unwrap();
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn read_index_reads() {
+    let replicas = setup_replicas(3).await;
+
+    tokio::task::spawn(async {
+        query(1, String::from("CREATE TABLE IF NOT EXISTS test_read_index (id integer PRIMARY KEY)")).await.unwrap();
+        query(1, String::from("INSERT INTO test_read_index VALUES(1)")).await.unwrap();
+
+        // every node, leader or not, sees the completed write
+        for id in 1..=3 {
+            let mut client = RpcClient::connect(node_rpc_addr(id)).await.unwrap();
+            let request = tonic::Request::new(Query {
+                sql: String::from("SELECT COUNT(*) FROM test_read_index"),
+                consistency: Consistency::ReadIndex as i32,
+                ..Default::default()
+            });
+            let res = client.execute(request).await.unwrap().into_inner();
+            assert!(value_to_string(&res.rows[0].values[0]) == "1");
+        }
+
+        query(1, String::from("DROP TABLE test_read_index")).await.unwrap();
+    }).await.unwrap();
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn leader_lease_reads() {
+    let lease = LeaderLease {
+        duration: tokio::time::Duration::from_secs(2),
+        ..Default::default()
+    };
+    let mut replicas = Vec::new();
+    for (id, peers) in [(1, vec![2, 3]), (2, vec![1, 3]), (3, vec![1, 2])] {
+        remove_replica_state(id);
+        let mut config = StoreConfig::default();
+        config.set_leader_lease(Some(lease));
+        replicas.push(start_replica_with_config(id, peers, config).await);
+    }
+
+    tokio::task::spawn(async move {
+        query(1, String::from("CREATE TABLE IF NOT EXISTS test_lease (id integer PRIMARY KEY)")).await.unwrap();
+
+        // reads stay linearizable while the lease is renewed
+        for i in 1..=3 {
+            query(1, format!("INSERT INTO test_lease VALUES({})", i)).await.unwrap();
+            for id in 1..=3 {
+                let mut client = RpcClient::connect(node_rpc_addr(id)).await.unwrap();
+                let request = tonic::Request::new(Query {
+                    sql: String::from("SELECT COUNT(*) FROM test_lease"),
+                    consistency: Consistency::ReadIndex as i32,
+                    ..Default::default()
+                });
+                let res = client.execute(request).await.unwrap().into_inner();
+                assert!(value_to_string(&res.rows[0].values[0]) == i.to_string());
+            }
+            tokio::time::sleep(lease.duration).await;
+        }
+    }).await.unwrap();
+
+    // without its followers, the leader reads on its lease alone, as no quorum
+    // can confirm it anymore
+    let leader_idx = replicas.iter().position(|r| r.is_leader()).unwrap();
+    let leader = replicas.remove(leader_idx);
+    shutdown_replicas(replicas).await;
+    let leader_id = leader.get_id();
+    let read = move || async move {
+        let mut client = RpcClient::connect(node_rpc_addr(leader_id)).await.unwrap();
+        let request = tonic::Request::new(Query {
+            sql: String::from("SELECT COUNT(*) FROM test_lease"),
+            consistency: Consistency::ReadIndex as i32,
+            ..Default::default()
+        });
+        client.execute(request).await
+    };
+    tokio::task::spawn(async move {
+        let res = read().await.unwrap().into_inner();
+        assert!(value_to_string(&res.rows[0].values[0]) == "3");
+
+        // once the lease runs out, the leader has to confirm itself again
+        tokio::time::sleep(lease.duration).await;
+        assert!(read().await.is_err());
+    }).await.unwrap();
+
+    leader.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn lease_defers_new_leader() {
+    let lease = LeaderLease {
+        duration: tokio::time::Duration::from_secs(3),
+        ..Default::default()
+    };
+    let mut replicas = Vec::new();
+    for (id, peers) in [(1, vec![2, 3]), (2, vec![1, 3]), (3, vec![1, 2])] {
+        remove_replica_state(id);
+        let mut config = StoreConfig::default();
+        config.set_leader_lease(Some(lease));
+        replicas.push(start_replica_with_config(id, peers, config).await);
+    }
+
+    tokio::task::spawn(async {
+        query(1, String::from("CREATE TABLE IF NOT EXISTS test_lease_defer (id integer PRIMARY KEY)")).await.unwrap();
+    }).await.unwrap();
+
+    // the followers granted the crashed leader a lease, and do not accept
+    // another leader before it runs out
+    let leader_idx = replicas.iter().position(|r| r.is_leader()).unwrap();
+    let leader = replicas.remove(leader_idx);
+    let old_leader = leader.get_id();
+    let crashed_at = std::time::Instant::now();
+    leader.shutdown().await;
+    while replicas.iter().any(|r| r.get_current_leader() == old_leader) {
+        assert!(crashed_at.elapsed() < tokio::time::Duration::from_secs(30));
+        tokio::time::sleep(tokio::time::Duration::from_millis(50)).await;
+    }
+    assert!(crashed_at.elapsed() >= lease.duration - tokio::time::Duration::from_millis(500));
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn read_your_writes() {
+    let replicas = setup_replicas(3).await;
+
+    let read = |id: u64, config_id: u32, token: u64| async move {
+        let mut client = RpcClient::connect(node_rpc_addr(id)).await.unwrap();
+        let request = tonic::Request::new(Query {
+            sql: String::from("SELECT COUNT(*) FROM test_ryw"),
+            consistency: Consistency::MinIndex as i32,
+            min_index_config: config_id,
+            min_index: token,
+            ..Default::default()
+        });
+        client.execute(request).await.unwrap().into_inner()
+    };
+    let write = |i: u64| async move {
+        let mut client = RpcClient::connect(node_rpc_addr(1)).await.unwrap();
+        let request = tonic::Request::new(Query {
+            sql: format!("INSERT INTO test_ryw VALUES({})", i),
+            ..Default::default()
+        });
+        client.execute(request).await.unwrap().into_inner()
+    };
+
+    let old_token = tokio::task::spawn(async move {
+        query(1, String::from("CREATE TABLE IF NOT EXISTS test_ryw (id integer PRIMARY KEY)")).await.unwrap();
+
+        let mut token = (0, 0);
+        for i in 1..=3 {
+            let res = write(i).await;
+            token = (res.config_id, res.applied_idx);
+            assert!(res.applied_idx > 0);
+
+            // any node reads the write once it has applied the token
+            for id in 1..=3 {
+                let res = read(id, token.0, token.1).await;
+                assert!(value_to_string(&res.rows[0].values[0]) == i.to_string());
+                assert!((res.config_id, res.applied_idx) >= token);
+            }
+        }
+        token
+    }).await.unwrap();
+
+    // indices restart with a new configuration, tokens stay ordered
+    let leader = replicas.iter().find(|r| r.is_leader()).unwrap();
+    leader.reconfigure(vec![1, 2, 3]);
+    tokio::time::sleep(tokio::time::Duration::from_millis(2000)).await; // wait for reconfiguration
+
+    tokio::task::spawn(async move {
+        let res = write(4).await;
+        assert!(res.config_id > old_token.0);
+        for id in 1..=3 {
+            let res = read(id, res.config_id, res.applied_idx).await;
+            assert!(value_to_string(&res.rows[0].values[0]) == "4");
+            let res = read(id, old_token.0, old_token.1).await;
+            assert!(value_to_string(&res.rows[0].values[0]) == "4");
+        }
+
+        query(1, String::from("DROP TABLE test_ryw")).await.unwrap();
+    }).await.unwrap();
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn read_only_routing() {
+    let replicas = setup_replicas(2).await;
+
+    tokio::task::spawn(async {
+        query(1, String::from("CREATE TABLE IF NOT EXISTS test_routing (id integer PRIMARY KEY)")).await.unwrap();
+        query(1, String::from("INSERT INTO test_routing VALUES(1)")).await.unwrap();
+
+        let mut client = RpcClient::connect(node_rpc_addr(2)).await.unwrap();
+        let execute = |sql: &str| tonic::Request::new(Query { sql: sql.to_string(), ..Default::default() });
+
+        // reads do not grow the log, their results carry the index they read at
+        let read = client.execute(execute("SELECT COUNT(*) FROM test_routing")).await.unwrap().into_inner();
+        assert!(value_to_string(&read.rows[0].values[0]) == "1");
+        let again = client.execute(execute("SELECT COUNT(*) FROM test_routing")).await.unwrap().into_inner();
+        assert!(again.applied_idx == read.applied_idx);
+
+        // writes and statements that cannot be classified are replicated
+        let write = client.execute(execute("INSERT INTO test_routing VALUES(2)")).await.unwrap().into_inner();
+        assert!(write.applied_idx == read.applied_idx + 1);
+        assert!(client.execute(execute("SELECT * FROM test_missing")).await.is_err());
+        let res = query(1, String::from("SELECT COUNT(*) FROM test_routing")).await.unwrap();
+        assert!(res == "2");
+
+        query(1, String::from("DROP TABLE test_routing")).await.unwrap();
+    }).await.unwrap();
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn execute_stream() {
+    let replicas = setup_replicas(2).await;
+
+    tokio::task::spawn(async {
+        query(1, String::from("CREATE TABLE IF NOT EXISTS test_stream (id integer PRIMARY KEY)")).await.unwrap();
+        query(1, String::from(
+            "WITH RECURSIVE ids(id) AS (SELECT 1 UNION ALL SELECT id + 1 FROM ids WHERE id < 600) \
+             INSERT INTO test_stream SELECT id FROM ids",
+        )).await.unwrap();
+
+        let mut client = RpcClient::connect(node_rpc_addr(2)).await.unwrap();
+        let execute = |sql: &str| tonic::Request::new(Query { sql: sql.to_string(), ..Default::default() });
+
+        // rows arrive in batches, only the first carries the columns
+        let mut stream = client.execute_stream(execute("SELECT id FROM test_stream ORDER BY id")).await.unwrap().into_inner();
+        let mut batches = vec![];
+        while let Some(batch) = stream.message().await.unwrap() {
+            batches.push(batch);
+        }
+        assert!(batches.len() == 3);
+        assert!(batches[0].columns.len() == 1);
+        assert!(batches[1..].iter().all(|batch| batch.columns.is_empty()));
+        let rows: Vec<_> = batches.iter().flat_map(|batch| batch.rows.iter()).collect();
+        assert!(rows.len() == 600);
+        assert!(value_to_string(&rows[599].values[0]) == "600");
+
+        // writes are replicated and streamed once applied
+        let mut stream = client.execute_stream(execute("DELETE FROM test_stream WHERE id > 300")).await.unwrap().into_inner();
+        let write = stream.message().await.unwrap().unwrap();
+        assert!(write.rows_affected == 300);
+        assert!(stream.message().await.unwrap().is_none());
+
+        // a paused stream does not hold up writes
+        query(1, String::from(
+            "WITH RECURSIVE ids(id) AS (SELECT 301 UNION ALL SELECT id + 1 FROM ids WHERE id < 3000) \
+             INSERT INTO test_stream SELECT id FROM ids",
+        )).await.unwrap();
+        let mut stream = client.execute_stream(execute("SELECT id FROM test_stream ORDER BY id")).await.unwrap().into_inner();
+        assert!(stream.message().await.unwrap().is_some());
+        let started = std::time::Instant::now();
+        query(2, String::from("INSERT INTO test_stream VALUES(3001)")).await.unwrap();
+        assert!(started.elapsed() < tokio::time::Duration::from_millis(800));
+
+        // a slow receiver is waited for, and sees the state the stream started at
+        tokio::time::sleep(tokio::time::Duration::from_millis(1500)).await;
+        let mut rows = 256;
+        while let Some(batch) = stream.message().await.unwrap() {
+            rows += batch.rows.len();
+        }
+        assert!(rows == 3000);
+
+        query(1, String::from("DROP TABLE test_stream")).await.unwrap();
+    }).await.unwrap();
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn query_deadlines() {
+    let mut replicas = setup_replicas(2).await;
+
+    query(1, String::from("CREATE TABLE IF NOT EXISTS test_deadline (id integer PRIMARY KEY)")).await.unwrap();
+
+    // without its peer the write is never decided
+    replicas.pop().unwrap().shutdown().await;
+    let server = replicas[0].store_server.clone();
+
+    tokio::task::spawn(async move {
+        let timeout = Some(std::time::Duration::from_millis(200));
+        let res = server.query("INSERT INTO test_deadline VALUES(1)", chiselstore::Consistency::Strong, timeout).await;
+        assert!(matches!(res, Err(StoreError::Timeout)));
+
+        // the client deadline is honoured as well
+        let mut client = RpcClient::connect(node_rpc_addr(1)).await.unwrap();
+        let mut request = tonic::Request::new(Query {
+            sql: String::from("INSERT INTO test_deadline VALUES(2)"),
+            ..Default::default()
+        });
+        request.metadata_mut().insert("grpc-timeout", "200m".parse().unwrap());
+        let status = client.execute(request).await.unwrap_err();
+        assert!(status.code() == tonic::Code::DeadlineExceeded);
+    }).await.unwrap();
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn removed_node_drops_proposals() {
+    let replicas = setup_replicas(3).await;
+
+    query(1, String::from("CREATE TABLE IF NOT EXISTS test_dropped (id integer PRIMARY KEY)")).await.unwrap();
+
+    // remove the last node from the cluster
+    let leader = replicas.iter().find(|r| r.is_leader()).unwrap();
+    let removed_id = replicas.iter().map(|r| r.get_id()).filter(|&id| id != leader.get_id()).max().unwrap();
+    let new_cluster = replicas.iter().map(|r| r.get_id()).filter(|&id| id != removed_id).collect();
+    leader.reconfigure(new_cluster);
+    tokio::time::sleep(tokio::time::Duration::from_millis(2000)).await; // wait for reconfiguration
+
+    tokio::task::spawn(async move {
+        // the stopped configuration takes no more writes, which are safe to retry elsewhere
+        let mut client = RpcClient::connect(node_rpc_addr(removed_id)).await.unwrap();
+        let request = tonic::Request::new(Query {
+            sql: String::from("INSERT INTO test_dropped VALUES(1)"),
+            ..Default::default()
+        });
+        let status = client.execute(request).await.unwrap_err();
+        assert!(status.code() == tonic::Code::Unavailable);
+    }).await.unwrap();
+
+    let living_replica_id = leader.get_id();
+    tokio::task::spawn(async move {
+        query(living_replica_id, String::from("DROP TABLE test_dropped")).await.unwrap();
+    }).await.unwrap();
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn results_reach_their_node() {
+    let replicas = setup_replicas(2).await;
+
+    tokio::task::spawn(async {
+        query(1, String::from("CREATE TABLE IF NOT EXISTS test_origin (id integer PRIMARY KEY, node integer)")).await.unwrap();
+
+        // both nodes propose commands with the same sequence numbers
+        let insert = |node: u64, rows: u64, round: u64| async move {
+            let mut client = RpcClient::connect(node_rpc_addr(node)).await.unwrap();
+            let values: Vec<String> = (0..rows).map(|row| format!("({}, {})", node * 1000 + round * 10 + row, node)).collect();
+            let request = tonic::Request::new(Query {
+                sql: format!("INSERT INTO test_origin VALUES {}", values.join(", ")),
+                ..Default::default()
+            });
+            client.execute(request).await.unwrap().into_inner()
+        };
+        for round in 0..5 {
+            let (one, two) = tokio::join!(insert(1, 1, round), insert(2, 2, round));
+            assert!(one.rows_affected == 1);
+            assert!(two.rows_affected == 2);
+        }
+
+        query(1, String::from("DROP TABLE test_origin")).await.unwrap();
+    }).await.unwrap();
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn retried_requests_apply_once() {
+    let mut replicas = Vec::new();
+    for (id, peers) in [(1, vec![2]), (2, vec![1])] {
+        remove_replica_state(id);
+        let mut config = StoreConfig::default();
+        config.set_dedup_window(2);
+        config.set_dedup_expiry(2);
+        replicas.push(start_replica_with_config(id, peers, config).await);
+    }
+
+    tokio::task::spawn(async {
+        query(1, String::from("CREATE TABLE IF NOT EXISTS test_dedup (id integer PRIMARY KEY AUTOINCREMENT)")).await.unwrap();
+
+        let request = |seq: u64| Some(RequestId { client_id: String::from("client"), seq });
+        let insert = |node: u64, seq: u64| async move {
+            let mut client = RpcClient::connect(node_rpc_addr(node)).await.unwrap();
+            client.execute(tonic::Request::new(Query {
+                sql: String::from("INSERT INTO test_dedup DEFAULT VALUES"),
+                request: request(seq),
+                ..Default::default()
+            })).await
+        };
+
+        // a retry on any node is answered with the results of the first attempt
+        let first = insert(1, 1).await.unwrap().into_inner();
+        let retry = insert(2, 1).await.unwrap().into_inner();
+        assert!(retry.last_insert_rowid == first.last_insert_rowid);
+        let res = query(1, String::from("SELECT COUNT(*) FROM test_dedup")).await.unwrap();
+        assert!(res == "1");
+
+        let mut client = RpcClient::connect(node_rpc_addr(1)).await.unwrap();
+        let batch = BatchReq {
+            statements: vec![Statement { sql: String::from("INSERT INTO test_dedup DEFAULT VALUES"), ..Default::default() }],
+            request: request(2),
+        };
+        client.batch(tonic::Request::new(batch.clone())).await.unwrap();
+        client.batch(tonic::Request::new(batch)).await.unwrap();
+        insert(1, 3).await.unwrap();
+        let res = query(1, String::from("SELECT COUNT(*) FROM test_dedup")).await.unwrap();
+        assert!(res == "3");
+
+        // only the results of the latest requests are kept
+        assert!(insert(1, 2).await.is_ok());
+        let status = insert(1, 1).await.unwrap_err();
+        assert!(status.code() == tonic::Code::FailedPrecondition);
+        let res = query(1, String::from("SELECT COUNT(*) FROM test_dedup")).await.unwrap();
+        assert!(res == "3");
+
+        // a retry with other statements is rejected
+        let status = client.execute(tonic::Request::new(Query {
+            sql: String::from("DELETE FROM test_dedup"),
+            request: request(3),
+            ..Default::default()
+        })).await.unwrap_err();
+        assert!(status.code() == tonic::Code::InvalidArgument);
+        let res = query(1, String::from("SELECT COUNT(*) FROM test_dedup")).await.unwrap();
+        assert!(res == "3");
+
+        // the results of a client that sent none of the latest requests expire
+        for seq in 1..=2 {
+            client.execute(tonic::Request::new(Query {
+                sql: String::from("INSERT INTO test_dedup DEFAULT VALUES"),
+                request: Some(RequestId { client_id: String::from("other"), seq }),
+                ..Default::default()
+            })).await.unwrap();
+        }
+        let res = query(1, String::from("SELECT COUNT(*) FROM _chisel_dedup WHERE client_id = 'client'")).await.unwrap();
+        assert!(res == "0");
+        let res = query(1, String::from("SELECT COUNT(*) FROM _chisel_dedup_clients")).await.unwrap();
+        assert!(res == "1");
+
+        query(1, String::from("DROP TABLE test_dedup")).await.unwrap();
+    }).await.unwrap();
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn cluster_restarts() {
+    // Write to cluster, restart every node, read written value back
+    let replicas = setup_replicas(2).await;
+
+    // START: test
+
+    tokio::task::spawn(async {
+        query(1, String::from("CREATE TABLE IF NOT EXISTS test_restart (id integer PRIMARY KEY)")).await.unwrap();
+        query(1, String::from("INSERT INTO test_restart VALUES(1)")).await.unwrap();
+    }).await.unwrap();
+
+    shutdown_replicas(replicas).await;
+
+    // restart on top of the durable state
+    let replicas = vec![start_replica(1, vec![2]).await, start_replica(2, vec![1]).await];
+
+    tokio::task::spawn(async {
+        let res = query(2, String::from("SELECT id FROM test_restart WHERE id = 1")).await.unwrap();
+        assert!(res == "1");
+
+        // new entries are appended after the recovered ones
+        query(1, String::from("INSERT INTO test_restart VALUES(2)")).await.unwrap();
+        let res = query(1, String::from("SELECT COUNT(*) FROM test_restart")).await.unwrap();
+        assert!(res == "2");
+    }).await.unwrap();
+
+    // END: Test
+
+    // drop table
+    query(1, String::from("DROP TABLE test_restart")).await.unwrap();
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn restart_applies_entries_once() {
+    // Entries applied before a restart are not applied again
+    let replicas = setup_replicas(2).await;
+
+    tokio::task::spawn(async {
+        query(1, String::from("CREATE TABLE IF NOT EXISTS test_apply_once (value integer)")).await.unwrap();
+        query(1, String::from("INSERT INTO test_apply_once VALUES(1)")).await.unwrap();
+    }).await.unwrap();
+
+    shutdown_replicas(replicas).await;
+
+    let replicas = vec![start_replica(1, vec![2]).await, start_replica(2, vec![1]).await];
+
+    tokio::task::spawn(async {
+        let res = query(1, String::from("SELECT COUNT(*) FROM test_apply_once")).await.unwrap();
+        assert!(res == "1");
+        let res = query(2, String::from("SELECT COUNT(*) FROM test_apply_once")).await.unwrap();
+        assert!(res == "1");
+    }).await.unwrap();
+
+    query(1, String::from("DROP TABLE test_apply_once")).await.unwrap();
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn log_compaction() {
+    let mut replicas = Vec::new();
+    for (id, peers) in [(1, vec![2]), (2, vec![1])] {
+        remove_replica_state(id);
+        let mut config = StoreConfig::default();
+        config.set_compaction_policy(CompactionPolicy {
+            max_entries: Some(10),
+            max_bytes: None,
+            interval: None,
+            mode: CompactionMode::Trim,
+        });
+        replicas.push(start_replica_with_config(id, peers, config).await);
+    }
+    let compacted_idx = |replicas: &Vec<Replica>| replicas.iter().map(|r| r.store_server.get_compacted_idx()).collect::<Vec<_>>();
+    let wait_for_compaction = |replicas: &Vec<Replica>, above: u64| {
+        let compacted = replicas.iter().map(|r| r.store_server.clone()).collect::<Vec<_>>();
+        async move {
+            for _ in 0..100 {
+                if compacted.iter().all(|server| server.get_compacted_idx() > above) {
+                    return true;
+                }
+                tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+            }
+            false
+        }
+    };
+
+    // nothing is compacted below the threshold
+    tokio::task::spawn(async {
+        query(1, String::from("CREATE TABLE IF NOT EXISTS test_compaction (id integer PRIMARY KEY)")).await.unwrap();
+        query(1, String::from("INSERT INTO test_compaction VALUES(1)")).await.unwrap();
+    }).await.unwrap();
+    tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+    assert!(compacted_idx(&replicas) == vec![0, 0]);
+
+    // every replica trims its log once the threshold is reached
+    tokio::task::spawn(async {
+        for i in 2..=12 {
+            query(1, format!("INSERT INTO test_compaction VALUES({})", i)).await.unwrap();
+        }
+    }).await.unwrap();
+    assert!(wait_for_compaction(&replicas, 0).await);
+    let trimmed = compacted_idx(&replicas).into_iter().min().unwrap();
+
+    // a non-empty log is compacted once the interval passed
+    for replica in replicas.iter() {
+        replica.store_server.set_compaction_policy(CompactionPolicy {
+            max_entries: None,
+            max_bytes: None,
+            interval: Some(std::time::Duration::from_millis(200)),
+            mode: CompactionMode::Trim,
+        });
+    }
+    tokio::task::spawn(async {
+        query(1, String::from("INSERT INTO test_compaction VALUES(13)")).await.unwrap();
+    }).await.unwrap();
+    assert!(wait_for_compaction(&replicas, trimmed).await);
+
+    tokio::task::spawn(async {
+        let res = query(2, String::from("SELECT COUNT(*) FROM test_compaction")).await.unwrap();
+        assert!(res == "13");
+        query(1, String::from("DROP TABLE test_compaction")).await.unwrap();
+    }).await.unwrap();
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn lagging_node_catches_up_from_snapshot() {
+    let config = || {
+        let mut config = StoreConfig::default();
+        config.set_compaction_policy(CompactionPolicy {
+            max_entries: Some(5),
+            max_bytes: None,
+            interval: None,
+            mode: CompactionMode::Snapshot,
+        });
+        config
+    };
+    let peers = |id: u64| (1..=3).filter(|peer| *peer != id).collect::<Vec<u64>>();
+    let mut replicas = Vec::new();
+    for id in 1..=3 {
+        remove_replica_state(id);
+        replicas.push(start_replica_with_config(id, peers(id), config()).await);
+    }
+
+    tokio::task::spawn(async {
+        query(1, String::from("CREATE TABLE IF NOT EXISTS test_snapshot (id integer PRIMARY KEY, value integer)")).await.unwrap();
+        query(1, String::from("CREATE TABLE IF NOT EXISTS test_snapshot_audit (id integer)")).await.unwrap();
+        query(1, String::from("CREATE INDEX IF NOT EXISTS test_snapshot_value ON test_snapshot (value)")).await.unwrap();
+        query(1, String::from(
+            "CREATE TRIGGER IF NOT EXISTS test_snapshot_insert AFTER INSERT ON test_snapshot \
+             BEGIN INSERT INTO test_snapshot_audit VALUES (new.id); END"
+        )).await.unwrap();
+    }).await.unwrap();
+
+    // a follower misses the entries compacted into the snapshot
+    let lagging = replicas.iter().position(|r| !r.is_leader()).unwrap();
+    let lagging_id = replicas[lagging].get_id();
+    let leader_id = if lagging_id == 1 { 2 } else { 1 };
+    replicas.remove(lagging).shutdown().await;
+
+    tokio::task::spawn(async move {
+        let mut client = RpcClient::connect(node_rpc_addr(leader_id)).await.unwrap();
+        for i in 1..=10 {
+            client.execute(tonic::Request::new(Query {
+                sql: format!("INSERT INTO test_snapshot VALUES({}, {})", i, i * 10),
+                request: Some(RequestId { client_id: String::from("client"), seq: i }),
+                ..Default::default()
+            })).await.unwrap();
+        }
+    }).await.unwrap();
+    let mut compacted = false;
+    for _ in 0..100 {
+        if replicas.iter().any(|r| r.store_server.get_compacted_idx() > 0) {
+            compacted = true;
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+    assert!(compacted);
+
+    replicas.push(start_replica_with_config(lagging_id, peers(lagging_id), config()).await);
+
+    tokio::task::spawn(async move {
+        let res = query(lagging_id, String::from("SELECT COUNT(*) FROM test_snapshot")).await.unwrap();
+        assert!(res == "10");
+        let res = query(lagging_id, String::from(
+            "SELECT COUNT(*) FROM sqlite_master WHERE name IN ('test_snapshot_value', 'test_snapshot_insert')"
+        )).await.unwrap();
+        assert!(res == "2");
+        let res = query(lagging_id, String::from("SELECT COUNT(*) FROM _chisel_dedup WHERE client_id = 'client'")).await.unwrap();
+        assert!(res == "10");
+        let res = query(lagging_id, String::from("SELECT COUNT(*) FROM _chisel_meta")).await.unwrap();
+        assert!(res == "1");
+
+        // the restored trigger and deduplicated requests keep working
+        let mut client = RpcClient::connect(node_rpc_addr(lagging_id)).await.unwrap();
+        client.execute(tonic::Request::new(Query {
+            sql: String::from("INSERT INTO test_snapshot VALUES(1, 10)"),
+            request: Some(RequestId { client_id: String::from("client"), seq: 1 }),
+            ..Default::default()
+        })).await.unwrap();
+        query(leader_id, String::from("INSERT INTO test_snapshot VALUES(11, 110)")).await.unwrap();
+        let res = query(lagging_id, String::from("SELECT COUNT(*) FROM test_snapshot_audit")).await.unwrap();
+        assert!(res == "11");
+    }).await.unwrap();
+
+    // the log the follower appends to after the snapshot survives a restart
+    let lagging = replicas.iter().position(|r| r.get_id() == lagging_id).unwrap();
+    replicas.remove(lagging).shutdown().await;
+    replicas.push(start_replica_with_config(lagging_id, peers(lagging_id), config()).await);
+
+    tokio::task::spawn(async move {
+        query(leader_id, String::from("INSERT INTO test_snapshot VALUES(12, 120)")).await.unwrap();
+        let res = query(lagging_id, String::from("SELECT COUNT(*) FROM test_snapshot")).await.unwrap();
+        assert!(res == "12");
+
+        query(leader_id, String::from("DROP TABLE test_snapshot")).await.unwrap();
+        query(leader_id, String::from("DROP TABLE test_snapshot_audit")).await.unwrap();
+    }).await.unwrap();
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn snapshot_transfer_resumes() {
+    remove_replica_state(1);
+    let transport = RpcTransport::new(Box::new(node_rpc_addr));
+    let mut config = StoreConfig::default();
+    config.set_data_dir(node_data_dir(1));
+    let server = StoreServer::start_with_config(1, vec![2], transport, config).unwrap();
+
+    let image: Vec<u8> = (0..100).collect();
+    let size = image.len() as u64;
+    let checksum = crc32fast::hash(&image);
+    let write = |idx: u64, checksum: u32, offset: usize, data: &[u8]| {
+        server.write_snapshot_chunk(idx, size, checksum, offset as u64, data, crc32fast::hash(data))
+    };
+
+    // a damaged chunk is rejected without being stored
+    let res = server.write_snapshot_chunk(7, size, checksum, 0, &image[..40], !crc32fast::hash(&image[..40]));
+    assert!(matches!(res, Err(StoreError::CorruptSnapshot(_))));
+    assert!(server.snapshot_transfer_offset(7, size, checksum).unwrap() == 0);
+
+    // an interrupted transfer resumes where it stopped
+    assert!(write(7, checksum, 0, &image[..40]).unwrap() == 40);
+    assert!(server.snapshot_transfer_offset(7, size, checksum).unwrap() == 40);
+    assert!(server.staged_snapshot_image(7, size, checksum).is_err());
+    assert!(write(7, checksum, 0, &image[..40]).unwrap() == 40);
+    assert!(write(7, checksum, 60, &image[60..]).unwrap() == 40);
+    assert!(write(7, checksum, 40, &image[40..]).unwrap() == size);
+    let staged = server.staged_snapshot_image(7, size, checksum).unwrap();
+    assert!(std::fs::read(&staged.path).unwrap() == image);
+    assert!(server.snapshot_transfer_offset(7, size, checksum).unwrap() == size);
+
+    // an image that does not match its checksum is transferred again
+    assert!(write(8, !checksum, 0, &image[..40]).unwrap() == 40);
+    let res = write(8, !checksum, 40, &image[40..]);
+    assert!(matches!(res, Err(StoreError::CorruptSnapshot(_))));
+    assert!(server.snapshot_transfer_offset(8, size, !checksum).unwrap() == 0);
+    assert!(server.staged_snapshot_image(8, size, !checksum).is_err());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn corrupted_log_is_detected() {
+    let replicas = setup_replicas(2).await;
+
+    tokio::task::spawn(async {
+        query(1, String::from("CREATE TABLE IF NOT EXISTS test_corrupt (id integer PRIMARY KEY)")).await.unwrap();
+        query(1, String::from("INSERT INTO test_corrupt VALUES(1)")).await.unwrap();
+    }).await.unwrap();
+
+    shutdown_replicas(replicas).await;
+
+    let start = || {
+        let transport = RpcTransport::new(Box::new(node_rpc_addr));
+        let mut config = StoreConfig::default();
+        config.set_data_dir(node_data_dir(2));
+        StoreServer::start_with_config(2, vec![1], transport, config)
+    };
+    let segment = node_data_dir(2).join("node2-config1-log").join(format!("{:020}.seg", 0));
+
+    // a torn write at the end of the log is truncated away
+    let mut bytes = std::fs::read(&segment).unwrap();
+    let len = bytes.len();
+    bytes.extend_from_slice(&[0xff, 0x00, 0x00]);
+    std::fs::write(&segment, &bytes).unwrap();
+    drop(start().unwrap());
+    assert!(std::fs::read(&segment).unwrap().len() == len);
+
+    // so is a torn record of the state log, but not a damaged one before its end
+    let state_wal = node_data_dir(2).join("node2-config1-log").join("state.wal");
+    let intact = std::fs::read(&state_wal).unwrap();
+    let mut bytes = intact.clone();
+    bytes.extend_from_slice(&[0x01, 0x00]);
+    std::fs::write(&state_wal, &bytes).unwrap();
+    drop(start().unwrap());
+    assert!(std::fs::read(&state_wal).unwrap() == intact);
+    let mut bytes = intact.clone();
+    bytes[12] ^= 0xff;
+    std::fs::write(&state_wal, &bytes).unwrap();
+    assert!(matches!(start(), Err(StoreError::CorruptLog(_))));
+    std::fs::write(&state_wal, &intact).unwrap();
+
+    // a damaged length is reported rather than taken for a torn write
+    let intact = std::fs::read(&segment).unwrap();
+    let mut bytes = intact.clone();
+    bytes[3] ^= 0x80;
+    std::fs::write(&segment, &bytes).unwrap();
+    assert!(matches!(start(), Err(StoreError::CorruptLog(_))));
+    std::fs::write(&segment, &intact).unwrap();
+
+    // so is a damaged entry followed by intact ones
+    let mut bytes = intact.clone();
+    bytes[12] ^= 0xff;
+    std::fs::write(&segment, &bytes).unwrap();
+    assert!(matches!(start(), Err(StoreError::CorruptLog(_))));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn data_dir_is_locked() {
+    let start = |id: u64, data_dir: std::path::PathBuf| {
+        let transport = RpcTransport::new(Box::new(node_rpc_addr));
+        let mut config = StoreConfig::default();
+        config.set_data_dir(data_dir);
+        StoreServer::start_with_config(id, vec![3 - id], transport, config)
+    };
+
+    // the data directory has to exist
+    let missing = std::env::temp_dir().join("chiselstore-tests").join("missing");
+    if missing.exists() {
+        std::fs::remove_dir_all(&missing).unwrap();
+    }
+    assert!(matches!(start(1, missing), Err(StoreError::DataDir(_))));
+
+    // a directory is used by one node at a time, whatever its id
+    let shared = std::env::temp_dir().join("chiselstore-tests").join("shared");
+    if shared.exists() {
+        std::fs::remove_dir_all(&shared).unwrap();
+    }
+    std::fs::create_dir_all(&shared).unwrap();
+    let first = start(1, shared.clone()).unwrap();
+    assert!(matches!(start(1, shared.clone()), Err(StoreError::DataDir(_))));
+    assert!(matches!(start(2, shared.clone()), Err(StoreError::DataDir(_))));
+
+    // the lock is released with the server
+    drop(first);
+    drop(start(2, shared).unwrap());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn in_memory_write_read() {
+    // create 2 replicas keeping their databases in memory
+    let mut replicas = Vec::new();
+    for (id, peers) in [(1, vec![2]), (2, vec![1])] {
+        remove_replica_state(id);
+        let mut config = StoreConfig::default();
+        config.set_storage_mode(StorageMode::Memory);
+        replicas.push(start_replica_with_config(id, peers, config).await);
+    }
+
+    // run test
+    tokio::task::spawn(async {
+        query(1, String::from("CREATE TABLE IF NOT EXISTS test_memory (id integer PRIMARY KEY)")).await.unwrap();
+        query(1, String::from("INSERT INTO test_memory VALUES(1)")).await.unwrap();
+
+        // every pooled connection sees the same database
+        for _ in 0..3 {
+            let res = query(1, String::from("SELECT id FROM test_memory WHERE id = 1")).await.unwrap();
+            assert!(res == "1");
+            let res = query(2, String::from("SELECT id FROM test_memory WHERE id = 1")).await.unwrap();
+            assert!(res == "1");
+        }
+    }).await.unwrap();
+
+    // nothing but the lock is written to the data directory
+    for id in 1..3 {
+        let files: Vec<_> = std::fs::read_dir(node_data_dir(id)).unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert!(files == vec![std::ffi::OsString::from("chiselstore.lock")]);
+    }
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn custom_log_storage() {
+    // create 2 replicas keeping their logs in a custom log storage
+    let mut replicas = Vec::new();
+    for (id, peers) in [(1, vec![2]), (2, vec![1])] {
+        remove_replica_state(id);
+        let mut config = StoreConfig::default();
+        config.set_log_storage(|_, _, _| Ok(Box::new(MemoryLogStorage::default()) as Box<dyn LogStorage>));
+        replicas.push(start_replica_with_config(id, peers, config).await);
+    }
+
+    // run test
+    tokio::task::spawn(async {
+        query(1, String::from("CREATE TABLE IF NOT EXISTS test_log_storage (id integer PRIMARY KEY)")).await.unwrap();
+        query(1, String::from("INSERT INTO test_log_storage VALUES(1)")).await.unwrap();
+
+        let res = query(2, String::from("SELECT id FROM test_log_storage WHERE id = 1")).await.unwrap();
+        assert!(res == "1");
+
+        query(1, String::from("DROP TABLE test_log_storage")).await.unwrap();
+    }).await.unwrap();
+
+    // the write-ahead log is never created
+    for id in 1..3 {
+        let has_wal = std::fs::read_dir(node_data_dir(id)).unwrap()
+            .any(|entry| entry.unwrap().path().extension().map_or(false, |ext| ext == "wal"));
+        assert!(!has_wal);
+    }
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn file_log_storage_restarts() {
+    // keep the logs in a single write-ahead log file per node
+    let config = || {
+        let mut config = StoreConfig::default();
+        config.set_log_storage(|_, id, configuration_id| {
+            let path = node_data_dir(id).join(format!("node{}-config{}.wal", id, configuration_id));
+            Ok(Box::new(FileLogStorage::open(path)?) as Box<dyn LogStorage>)
+        });
+        config
+    };
+    let mut replicas = Vec::new();
+    for (id, peers) in [(1, vec![2]), (2, vec![1])] {
+        remove_replica_state(id);
+        replicas.push(start_replica_with_config(id, peers, config()).await);
+    }
+
+    tokio::task::spawn(async {
+        query(1, String::from("CREATE TABLE IF NOT EXISTS test_file_log (id integer PRIMARY KEY)")).await.unwrap();
+        query(1, String::from("INSERT INTO test_file_log VALUES(1)")).await.unwrap();
+    }).await.unwrap();
+
+    shutdown_replicas(replicas).await;
+    for id in 1..3 {
+        assert!(node_data_dir(id).join(format!("node{}-config1.wal", id)).exists());
+    }
+
+    // restart on top of the write-ahead logs
+    let replicas = vec![
+        start_replica_with_config(1, vec![2], config()).await,
+        start_replica_with_config(2, vec![1], config()).await,
+    ];
+
+    tokio::task::spawn(async {
+        let res = query(2, String::from("SELECT COUNT(*) FROM test_file_log")).await.unwrap();
+        assert!(res == "1");
+        query(1, String::from("INSERT INTO test_file_log VALUES(2)")).await.unwrap();
+        let res = query(2, String::from("SELECT COUNT(*) FROM test_file_log")).await.unwrap();
+        assert!(res == "2");
+
+        query(1, String::from("DROP TABLE test_file_log")).await.unwrap();
+    }).await.unwrap();
+
+    shutdown_replicas(replicas).await;
+}
+
 #[tokio::test(flavor = "multi_thread")]
 async fn synchronous_writes() {
     // ChiselStore uses SQLite which only allows for synchronous writes